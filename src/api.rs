@@ -0,0 +1,259 @@
+use crate::config::ConfigFile;
+use crate::{SSHUser, UserExp, UserInfo, UserStatus};
+use axum::{
+    extract::{Path, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::Html,
+    routing::{get, post},
+    Json, Router,
+};
+use lazy_static::lazy_static;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    /// One-time secrets waiting to be viewed, keyed by token, with their expiry time.
+    static ref SECRETS: Mutex<HashMap<String, (String, Instant)>> = Mutex::new(HashMap::new());
+}
+
+/// Error body returned by the HTTP admin API.
+#[derive(Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+type ApiResult<T> = Result<Json<T>, (StatusCode, Json<ApiError>)>;
+
+/// Request body for creating a user manually.
+#[derive(Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    pub group: String,
+    pub exp_date: String,
+    pub password: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// Request body for creating a user automatically.
+#[derive(Deserialize)]
+pub struct AutoNewUser {
+    pub group: String,
+    pub days: i64,
+}
+
+/// Request body for renewing a user.
+#[derive(Deserialize)]
+pub struct Renew {
+    pub days: i64,
+}
+
+fn api_error(status: StatusCode, error: String) -> (StatusCode, Json<ApiError>) {
+    (status, Json(ApiError { error }))
+}
+
+/// Runs a call into the library on the blocking thread pool, since it runs commands and reads
+/// files that would otherwise stall the runtime the bots share.
+async fn run_blocking<T: Send + 'static>(
+    call: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> ApiResult<T> {
+    tokio::task::spawn_blocking(call)
+        .await
+        .map_err(|err| api_error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?
+        .map(Json)
+        .map_err(|err| api_error(StatusCode::BAD_REQUEST, err))
+}
+
+/// Compares two byte strings in time that only depends on their length, so the API key can't be
+/// guessed byte by byte from response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Checks the `Authorization: Bearer <api_key>` header of a request.
+fn authorize(headers: &HeaderMap, config: &ConfigFile) -> Result<(), (StatusCode, Json<ApiError>)> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match (token, config.api_key.as_deref()) {
+        (Some(token), Some(api_key)) if constant_time_eq(token.as_bytes(), api_key.as_bytes()) => {
+            Ok(())
+        }
+        _ => Err(api_error(
+            StatusCode::UNAUTHORIZED,
+            "Invalid API key".to_string(),
+        )),
+    }
+}
+
+async fn create_user(
+    State(config): State<Arc<ConfigFile>>,
+    headers: HeaderMap,
+    Json(body): Json<NewUser>,
+) -> ApiResult<SSHUser> {
+    authorize(&headers, &config)?;
+
+    run_blocking(move || {
+        crate::newuser(
+            &body.username,
+            &body.group,
+            &body.password,
+            &body.exp_date,
+            &body.groups,
+        )
+    })
+    .await
+}
+
+async fn auto_create_user(
+    State(config): State<Arc<ConfigFile>>,
+    headers: HeaderMap,
+    Json(body): Json<AutoNewUser>,
+) -> ApiResult<SSHUser> {
+    authorize(&headers, &config)?;
+
+    run_blocking(move || {
+        crate::validate_days(body.days, config.max_expiry_days)
+            .and_then(|_| crate::auto_newuser(&config.active_prefix(), &body.group, body.days))
+    })
+    .await
+}
+
+async fn user_info(
+    State(config): State<Arc<ConfigFile>>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> ApiResult<UserInfo> {
+    authorize(&headers, &config)?;
+
+    run_blocking(move || crate::get_user_info(&username)).await
+}
+
+async fn delete_user(
+    State(config): State<Arc<ConfigFile>>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> ApiResult<UserStatus> {
+    authorize(&headers, &config)?;
+
+    run_blocking(move || crate::userdel(&username, false)).await
+}
+
+async fn renew_user(
+    State(config): State<Arc<ConfigFile>>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+    Json(body): Json<Renew>,
+) -> ApiResult<UserExp> {
+    authorize(&headers, &config)?;
+
+    run_blocking(move || {
+        crate::validate_days(body.days, config.max_expiry_days)
+            .and_then(|_| crate::renew_user(&username, body.days))
+    })
+    .await
+}
+
+/// Stores a secret that can be viewed once at `/secret/<token>` until `ttl` passes.
+///
+/// Secrets are only kept in memory, so they're lost when the bot restarts.
+///
+/// # Arguments
+///
+/// * `secret` - The secret to store.
+/// * `ttl` - How long the secret can be viewed for.
+///
+/// # Returns
+///
+/// The random token of the secret.
+pub fn create_secret(secret: String, ttl: Duration) -> String {
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    let now = Instant::now();
+
+    let mut secrets = SECRETS.lock().unwrap_or_else(|err| err.into_inner());
+    secrets.retain(|_, (_, expires_at)| *expires_at > now);
+    secrets.insert(token.clone(), (secret, now + ttl));
+
+    token
+}
+
+/// Shows a button revealing the secret, so link previews don't consume it.
+async fn secret_page() -> Html<&'static str> {
+    Html(
+        "<!DOCTYPE html>\n<html><body>\n\
+         <p>This secret can only be viewed once.</p>\n\
+         <form method=\"post\"><button>Show</button></form>\n\
+         </body></html>\n",
+    )
+}
+
+/// Returns a secret and deletes it.
+async fn view_secret(Path(token): Path<String>) -> Result<String, StatusCode> {
+    let mut secrets = SECRETS.lock().unwrap_or_else(|err| err.into_inner());
+
+    match secrets.remove(&token) {
+        Some((secret, expires_at)) if expires_at > Instant::now() => Ok(secret),
+        _ => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Serves the HTTP admin API on the configured address.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration, providing the listen address and API key.
+pub async fn serve(config: ConfigFile) {
+    if config.api_key.is_none() {
+        log::error!("HTTP API is enabled but no api_key is configured, not starting it");
+        return;
+    }
+
+    let addr: SocketAddr = match config.api_address.parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            log::error!("Invalid api_address: {}", config.api_address);
+            return;
+        }
+    };
+
+    let app = Router::new()
+        .route("/users", post(create_user))
+        .route("/users/auto", post(auto_create_user))
+        .route("/users/:username", get(user_info).delete(delete_user))
+        .route("/users/:username/renew", post(renew_user))
+        .route("/secret/:token", get(secret_page).post(view_secret))
+        .with_state(Arc::new(config));
+
+    log::info!("Starting HTTP API on {addr}...");
+
+    if let Err(err) = axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await
+    {
+        log::error!("HTTP API stopped: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_compares_api_keys() {
+        assert!(constant_time_eq(b"secret-key", b"secret-key"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret-key", b"secret-kez"));
+        assert!(!constant_time_eq(b"secret-key", b"secret-ke"));
+        assert!(!constant_time_eq(b"secret-key", b""));
+    }
+}
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Number of entries kept, the oldest ones are dropped first.
+const MAX_ENTRIES: usize = 1000;
+
+/// Longest window `recent` looks back, about ten years. Larger values would overflow the date
+/// arithmetic, and entries are only kept since the bot started anyway.
+const MAX_RECENT_MINUTES: i64 = 10 * 366 * 24 * 60;
+
+lazy_static! {
+    /// Commands executed since the bot started, oldest first.
+    static ref ENTRIES: Mutex<VecDeque<AuditEntry>> = Mutex::new(VecDeque::new());
+}
+
+/// Represents a command executed by an admin.
+#[derive(Clone)]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    pub admin: i64,
+    pub command: String,
+    pub target: String,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "{} min ago, admin `{}`: /{} `{}`",
+            (Utc::now() - self.time).num_minutes(),
+            self.admin,
+            self.command,
+            self.target
+        )
+    }
+}
+
+/// Records a command executed by an admin.
+///
+/// # Arguments
+///
+/// * `admin` - The chat ID of the admin.
+/// * `command` - The name of the command, without the slash.
+/// * `target` - The first argument of the command, usually a username.
+pub fn record(admin: i64, command: &str, target: &str) {
+    let mut entries = ENTRIES.lock().unwrap_or_else(|err| err.into_inner());
+
+    if entries.len() == MAX_ENTRIES {
+        entries.pop_front();
+    }
+
+    entries.push_back(AuditEntry {
+        time: Utc::now(),
+        admin,
+        command: command.to_string(),
+        target: target.to_string(),
+    });
+}
+
+/// Gets the commands executed in the last `minutes` minutes, oldest first.
+///
+/// Entries are only kept in memory, so commands from before the bot started aren't included.
+pub fn recent(minutes: i64) -> Vec<AuditEntry> {
+    let since = Utc::now() - Duration::minutes(minutes.clamp(0, MAX_RECENT_MINUTES));
+    let entries = ENTRIES.lock().unwrap_or_else(|err| err.into_inner());
+
+    entries
+        .iter()
+        .filter(|entry| entry.time >= since)
+        .cloned()
+        .collect()
+}
+
+/// Drops the entries older than `days` days.
+///
+/// # Returns
+///
+/// The number of entries dropped.
+pub fn prune(days: u32) -> usize {
+    let since = Utc::now() - Duration::days(days.into());
+    let mut entries = ENTRIES.lock().unwrap_or_else(|err| err.into_inner());
+    let count = entries.len();
+
+    entries.retain(|entry| entry.time >= since);
+
+    count - entries.len()
+}
@@ -1,6 +1,58 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono_tz::Tz;
 use config::Config;
-use serde::{Deserialize, Serialize};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::fs::OpenOptions;
+use std::hash::Hash;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// The configuration loaded from `/etc/userbot.json`.
+    pub static ref CONFIG: ConfigFile =
+        ConfigFile::load().unwrap_or_else(|_| panic!("Couldn't load config file!"));
+
+    /// Admin user IDs last read from `admin_list_file`.
+    static ref FILE_ADMINS: RwLock<Vec<i64>> = RwLock::new(Vec::new());
+
+    /// Prefix set with `/setprefix` since the configuration was loaded.
+    static ref PREFIX_OVERRIDE: RwLock<Option<String>> = RwLock::new(None);
+}
+
+/// Path of the configuration file.
+const CONFIG_PATH: &str = "/etc/userbot.json";
+
+/// How `/autoadd` names new users after the prefix.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UsernameStrategy {
+    /// The next number after the highest existing one, like `user007`.
+    #[default]
+    Sequential,
+    /// Random lowercase letters and digits of the given length, like `userk3x9qa`.
+    Random(usize),
+    /// Today's date followed by the next number for that day, like `user24013102`.
+    DatePrefixed,
+}
+
+/// How passwords are hashed for `useradd -p` and `usermod -p`, which must be a scheme the system's
+/// `crypt` supports.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordHashScheme {
+    /// SHA-512 crypt, `$6$` hashes.
+    #[default]
+    Sha512Crypt,
+    /// yescrypt, `$y$` hashes, the default of recent `login.defs`. Needs `mkpasswd` from `whois`
+    /// to hash and `perl` to check passwords, both looked up in `PATH` when the config loads.
+    Yescrypt,
+}
 
 /// Struct representing the configuration file for the userbot.
 #[derive(Serialize, Deserialize, Clone)]
@@ -15,10 +67,365 @@ pub struct ConfigFile {
     pub location: String,
     /// List of user IDs designated as administrators.
     pub admin_list: Vec<i64>,
+    /// File with one additional admin user ID per line, reloaded periodically.
+    #[serde(default)]
+    pub admin_list_file: Option<String>,
+    /// Interval between reloads of `admin_list_file`, in seconds.
+    #[serde(default = "default_admin_list_reload_secs")]
+    pub admin_list_reload_secs: u64,
+    /// List of admin user IDs with additional privileges.
+    #[serde(default)]
+    pub super_admins: Vec<i64>,
     /// ID of the chat used for logging.
     pub log_chat: i64,
     /// Prefix used for userbot commands.
     pub prefix: String,
+    /// How `/autoadd` names new users after the prefix.
+    #[serde(default)]
+    pub username_strategy: UsernameStrategy,
+    /// Whether `/useradd` creates the next free numbered variant of a taken username instead of
+    /// only suggesting it.
+    #[serde(default)]
+    pub auto_retry_on_collision: bool,
+    /// Number of usernames tried when the one picked is taken.
+    #[serde(default = "default_collision_retry_limit")]
+    pub collision_retry_limit: u32,
+    /// Token for the optional sales bot, which only exposes `/autoadd`.
+    #[serde(default)]
+    pub sales_bot_token: Option<String>,
+    /// List of user IDs allowed to use the sales bot.
+    #[serde(default)]
+    pub sales_allowlist: Vec<i64>,
+    /// Maximum number of users each admin can own, keyed by their user ID.
+    #[serde(default, deserialize_with = "deserialize_parsed_keys")]
+    pub admin_quotas: HashMap<i64, u32>,
+    /// Maximum number of managed users on this server, across all admins.
+    #[serde(default)]
+    pub max_total_users: Option<usize>,
+    /// Username prefix of the users each admin creates automatically, keyed by their chat ID.
+    #[serde(default, deserialize_with = "deserialize_parsed_keys")]
+    pub admin_prefixes: HashMap<i64, String>,
+    /// Lowest `N` allowed for `maxN` groups.
+    #[serde(default)]
+    pub min_logins: Option<u32>,
+    /// Highest `N` allowed for `maxN` groups.
+    #[serde(default)]
+    pub max_logins: Option<u32>,
+    /// Whether `maxN` groups get a maxlogins limit in `/etc/security/limits.d` when used.
+    #[serde(default)]
+    pub manage_limits: bool,
+    /// Network interface `/ratelimit` shapes traffic on.
+    #[serde(default)]
+    pub rate_limit_interface: Option<String>,
+    /// Directory with the IP ranges of each country for `/geoblock`, as `<country code>.zone` files
+    /// with one CIDR range per line.
+    #[serde(default)]
+    pub geoip_zone_dir: Option<String>,
+    /// File the countries blocked by `/geoblock` are recorded in.
+    #[serde(default = "default_geoblock_file")]
+    pub geoblock_file: String,
+    /// Command `/restartssh` runs to reload the SSH server.
+    #[serde(default = "default_ssh_reload_command")]
+    pub ssh_reload_command: Vec<String>,
+    /// Whether a status message is posted and pinned in the log chat when the bot starts.
+    #[serde(default)]
+    pub startup_announcement: bool,
+    /// Group quarantined users are moved to.
+    #[serde(default)]
+    pub quarantine_group: Option<String>,
+    /// Groups users may be assigned to, any group is allowed when unset.
+    #[serde(default)]
+    pub allowed_groups: Option<Vec<String>>,
+    /// Format of expiry dates in bot replies, as a `chrono` format string.
+    #[serde(default)]
+    pub date_format: Option<String>,
+    /// Maximum number of days accepted by day-taking commands.
+    #[serde(default)]
+    pub max_expiry_days: Option<i64>,
+    /// Commands that are forwarded to the log chat, defaults to the commands that change users.
+    #[serde(default = "default_log_commands")]
+    pub log_commands: Vec<String>,
+    /// Commands that require an inline-keyboard confirmation before executing.
+    #[serde(default)]
+    pub confirm_commands: Vec<String>,
+    /// Whether the raw Sagernet link is included in the QR code caption.
+    #[serde(default = "default_true")]
+    pub show_raw_link: bool,
+    /// Whether new users also get their QR code as an SVG document, for printing.
+    #[serde(default)]
+    pub qr_svg: bool,
+    /// Directory containing `useradd`, `usermod`, `userdel` and `chage`, found on `PATH` when unset.
+    #[serde(default)]
+    pub sbin_path: Option<String>,
+    /// Directory containing the home directories of users.
+    #[serde(default = "default_home_base")]
+    pub home_base: String,
+    /// Template file installed into the home directory of new users.
+    #[serde(default)]
+    pub home_template: Option<String>,
+    /// Image sent before the QR code of every new user, for branding.
+    #[serde(default)]
+    pub welcome_image: Option<String>,
+    /// Bytes of `welcome_image`, read once when the configuration is loaded.
+    #[serde(skip)]
+    pub welcome_image_bytes: Option<Arc<[u8]>>,
+    /// Maximum number of QR codes, cards and bundles rendered at once.
+    #[serde(default = "default_max_image_jobs")]
+    pub max_image_jobs: usize,
+    /// Whether to reply with the fields parsed from user creation commands before creating the user.
+    #[serde(default)]
+    pub echo_parsed: bool,
+    /// Length of the unambiguous passwords generated for new users, the `SSHMGMT` style is used
+    /// when unset.
+    #[serde(default)]
+    pub unambiguous_password_length: Option<usize>,
+    /// How passwords are hashed.
+    #[serde(default)]
+    pub password_hash_scheme: PasswordHashScheme,
+    /// Whether new users must change their password at first login.
+    #[serde(default)]
+    pub force_password_change: bool,
+    /// ID of the chat periodic user backups are sent to.
+    #[serde(default)]
+    pub backup_chat: Option<i64>,
+    /// Interval between user backups, in hours.
+    #[serde(default = "default_backup_interval_hours")]
+    pub backup_interval_hours: u64,
+    /// Interval between sweeps locking expired users, in seconds. The sweep is disabled when unset.
+    #[serde(default)]
+    pub expiry_sweep_interval_secs: Option<u64>,
+    /// Maximum random delay added to each sweep interval, in seconds.
+    #[serde(default)]
+    pub expiry_sweep_jitter_secs: u64,
+    /// Days an expired user can still connect before its account is disabled.
+    #[serde(default)]
+    pub grace_period_days: u32,
+    /// Interval between checks for users created by `/useraddprecise` that reached their expiry
+    /// time, in seconds.
+    #[serde(default = "default_precise_expiry_check_secs")]
+    pub precise_expiry_check_secs: u64,
+    /// Days a user created by `/useraddonlogin` stays valid while waiting for its first login.
+    #[serde(default = "default_activation_window_days")]
+    pub activation_window_days: i64,
+    /// Interval between checks for first logins of users pending activation, in seconds.
+    #[serde(default = "default_activation_check_secs")]
+    pub activation_check_secs: u64,
+    /// Interval between checks for due expiry reminders set with `/reminders`, in seconds.
+    #[serde(default = "default_reminder_check_secs")]
+    pub reminder_check_secs: u64,
+    /// File the user creations scheduled with `/schedule` are stored in.
+    #[serde(default = "default_schedule_file")]
+    pub schedule_file: String,
+    /// Interval between checks for scheduled user creations that are due, in seconds.
+    #[serde(default = "default_schedule_check_secs")]
+    pub schedule_check_secs: u64,
+    /// Interval between reconciliations of the users with their comment fields, in seconds.
+    /// Reconciliation is disabled when unset.
+    #[serde(default)]
+    pub reconcile_interval_secs: Option<u64>,
+    /// Maximum number of distinct source IPs a user may connect from at once.
+    #[serde(default)]
+    pub max_distinct_ips: Option<u32>,
+    /// Interval between checks of `max_distinct_ips`, in seconds.
+    #[serde(default = "default_ip_check_interval_secs")]
+    pub ip_check_interval_secs: u64,
+    /// Number of failed logins within `failure_window_secs` after which a managed user is locked.
+    /// Locking on failed logins is disabled when unset.
+    #[serde(default)]
+    pub max_auth_failures: Option<u32>,
+    /// Window failed logins are counted in for `max_auth_failures`, in seconds.
+    #[serde(default = "default_failure_window_secs")]
+    pub failure_window_secs: u64,
+    /// Log sshd reports failed logins to.
+    #[serde(default = "default_auth_log_path")]
+    pub auth_log_path: String,
+    /// Days `/recent` keeps commands for, older ones are pruned hourly.
+    #[serde(default)]
+    pub audit_retention_days: Option<u32>,
+    /// Whether the HTTP admin API is enabled.
+    #[serde(default)]
+    pub api_enabled: bool,
+    /// Address the HTTP admin API listens on.
+    #[serde(default = "default_api_address")]
+    pub api_address: String,
+    /// Bearer token required by the HTTP admin API.
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// Public base URL of the HTTP API, used in one-time secret links.
+    #[serde(default)]
+    pub api_public_url: Option<String>,
+    /// How long one-time secret links can be opened for, in seconds.
+    #[serde(default = "default_secret_ttl_secs")]
+    pub secret_ttl_secs: u64,
+    /// Whether commands attempted by non-admins are reported to the log chat.
+    #[serde(default)]
+    pub alert_unauthorized: bool,
+    /// Minimum delay between reports of unauthorized commands from the same chat, in seconds.
+    #[serde(default = "default_unauthorized_alert_interval_secs")]
+    pub unauthorized_alert_interval_secs: u64,
+    /// Minimum delay between messages sent to the log chat, in milliseconds.
+    #[serde(default = "default_log_interval_ms")]
+    pub log_interval_ms: u64,
+    /// Error messages for exit codes of the user management binaries, keyed by exit code.
+    #[serde(default, deserialize_with = "deserialize_parsed_keys")]
+    pub error_code_map: HashMap<i32, String>,
+    /// IANA name of the timezone expiry dates are calculated in, the system's local one when unset.
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_collision_retry_limit() -> u32 {
+    10
+}
+
+fn default_activation_window_days() -> i64 {
+    30
+}
+
+fn default_max_image_jobs() -> usize {
+    2
+}
+
+fn default_reminder_check_secs() -> u64 {
+    3600
+}
+
+fn default_schedule_file() -> String {
+    "/etc/userbot-schedules.json".to_string()
+}
+
+fn default_schedule_check_secs() -> u64 {
+    300
+}
+
+fn default_activation_check_secs() -> u64 {
+    300
+}
+
+fn default_precise_expiry_check_secs() -> u64 {
+    60
+}
+
+fn default_ssh_reload_command() -> Vec<String> {
+    vec![
+        "systemctl".to_string(),
+        "reload".to_string(),
+        "sshd".to_string(),
+    ]
+}
+
+fn default_geoblock_file() -> String {
+    "/etc/userbot-geoblock".to_string()
+}
+
+fn default_admin_list_reload_secs() -> u64 {
+    60
+}
+
+fn default_log_commands() -> Vec<String> {
+    [
+        "lock",
+        "unlock",
+        "userdel",
+        "changemax",
+        "changepass",
+        "shell",
+        "changeexp",
+        "verifyexpiry",
+        "renew",
+        "extend",
+        "bulkrenew",
+        "renewuntil",
+        "useradd",
+        "useraddgroups",
+        "useraddprecise",
+        "useraddonlogin",
+        "autoadd",
+        "schedule",
+        "cancelschedule",
+        "clone",
+        "transfer",
+        "quarantine",
+        "release",
+        "freeze",
+        "reminders",
+        "unfreeze",
+        "ratelimit",
+        "geoblock",
+        "restartssh",
+        "setprefix",
+        "securepass",
+    ]
+    .iter()
+    .map(|command| command.to_string())
+    .collect()
+}
+
+fn default_home_base() -> String {
+    "/home".to_string()
+}
+
+fn default_backup_interval_hours() -> u64 {
+    24
+}
+
+fn default_ip_check_interval_secs() -> u64 {
+    60
+}
+
+fn default_failure_window_secs() -> u64 {
+    600
+}
+
+fn default_auth_log_path() -> String {
+    "/var/log/auth.log".to_string()
+}
+
+fn default_api_address() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+fn default_secret_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_unauthorized_alert_interval_secs() -> u64 {
+    600
+}
+
+fn default_log_interval_ms() -> u64 {
+    3000
+}
+
+/// Deserializes a map whose JSON keys are strings holding numbers, failing on a key that doesn't
+/// parse instead of keeping an entry that never matches. The `config` crate only hands out map keys
+/// as strings, so they're parsed here rather than by serde.
+fn deserialize_parsed_keys<'de, D, K, V>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: FromStr + Eq + Hash,
+    K::Err: fmt::Display,
+    V: Deserialize<'de>,
+{
+    HashMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| {
+            key.parse()
+                .map(|key| (key, value))
+                .map_err(|e| serde::de::Error::custom(format!("Invalid key {}: {}", key, e)))
+        })
+        .collect()
+}
+
+/// Returns whether a command is found in one of the `PATH` directories.
+fn command_in_path(name: &str) -> bool {
+    std::env::var_os("PATH").map_or(false, |path| {
+        std::env::split_paths(&path).any(|dir| dir.join(name).is_file())
+    })
 }
 
 impl fmt::Display for ConfigFile {
@@ -32,12 +439,288 @@ impl fmt::Display for ConfigFile {
 }
 
 impl ConfigFile {
+    /// Returns whether a user ID is listed in `admin_list` or `admin_list_file`.
+    pub fn is_admin(&self, id: i64) -> bool {
+        self.admin_list.contains(&id)
+            || FILE_ADMINS
+                .read()
+                .map_or(false, |file_admins| file_admins.contains(&id))
+    }
+
+    /// Returns the user IDs listed in `admin_list` and `admin_list_file`.
+    pub fn admins(&self) -> Vec<i64> {
+        let mut admins = self.admin_list.clone();
+
+        if let Ok(file_admins) = FILE_ADMINS.read() {
+            admins.extend(
+                file_admins
+                    .iter()
+                    .filter(|id| !self.admin_list.contains(id)),
+            );
+        }
+
+        admins
+    }
+
+    /// Reads the admin user IDs in `admin_list_file`, replacing the previously read ones.
+    ///
+    /// Empty lines and lines starting with `#` are skipped. The previous IDs are kept if the file
+    /// can't be read or has an invalid line.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of IDs read, or an error message if reading fails.
+    pub fn reload_admin_list_file(&self) -> Result<usize, String> {
+        let Some(admin_list_file) = &self.admin_list_file else {
+            return Ok(0);
+        };
+
+        let content = std::fs::read_to_string(admin_list_file)
+            .map_err(|e| format!("Couldn't read {}: {}", admin_list_file, e))?;
+
+        let file_admins = content
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.parse::<i64>()
+                    .map_err(|_| format!("Invalid admin ID in {}: {}", admin_list_file, line))
+            })
+            .collect::<Result<Vec<i64>, String>>()?;
+
+        let count = file_admins.len();
+        *FILE_ADMINS
+            .write()
+            .map_err(|_| "Admin list lock is poisoned".to_string())? = file_admins;
+
+        Ok(count)
+    }
+
+    /// Returns the username prefix of an admin, falling back to the active global prefix.
+    pub fn admin_prefix(&self, chat_id: i64) -> String {
+        match self.admin_prefixes.get(&chat_id) {
+            Some(prefix) => prefix.clone(),
+            None => self.active_prefix(),
+        }
+    }
+
+    /// Returns the global username prefix, as changed by `set_prefix` if it was called.
+    pub fn active_prefix(&self) -> String {
+        PREFIX_OVERRIDE
+            .read()
+            .ok()
+            .and_then(|prefix| prefix.clone())
+            .unwrap_or_else(|| self.prefix.clone())
+    }
+
+    /// Changes the global username prefix and saves it to the configuration file.
+    ///
+    /// The file is rewritten as formatted JSON, so its original formatting isn't kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The new prefix.
+    ///
+    /// # Returns
+    ///
+    /// An error message if the configuration file can't be read or written, in which case the
+    /// active prefix is unchanged.
+    pub fn set_prefix(&self, prefix: &str) -> Result<(), String> {
+        let content = std::fs::read_to_string(CONFIG_PATH)
+            .map_err(|e| format!("Couldn't read {}: {}", CONFIG_PATH, e))?;
+
+        let mut settings: serde_json::Value = serde_json::from_str(&content)
+            .map_err(|e| format!("Couldn't parse {}: {}", CONFIG_PATH, e))?;
+
+        settings
+            .as_object_mut()
+            .ok_or_else(|| format!("{} isn't a JSON object", CONFIG_PATH))?
+            .insert("prefix".to_string(), prefix.into());
+
+        let content = serde_json::to_string_pretty(&settings).map_err(|e| e.to_string())?;
+
+        // The config holds the bot token, so keep the original permissions, or 0600 if they can't
+        // be read.
+        let permissions = std::fs::metadata(CONFIG_PATH)
+            .map(|metadata| metadata.permissions())
+            .unwrap_or_else(|_| std::fs::Permissions::from_mode(0o600));
+
+        // Write a temporary file and rename it, so a failed write can't leave a truncated config.
+        let temp_path = format!("{}.tmp", CONFIG_PATH);
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .and_then(|mut file| {
+                // An existing temporary file keeps its mode, so set it explicitly.
+                file.set_permissions(permissions)?;
+                file.write_all((content + "\n").as_bytes())
+            })
+            .and_then(|_| std::fs::rename(&temp_path, CONFIG_PATH))
+            .map_err(|e| format!("Couldn't write {}: {}", CONFIG_PATH, e))?;
+
+        *PREFIX_OVERRIDE
+            .write()
+            .map_err(|_| "Prefix lock is poisoned".to_string())? = Some(prefix.to_string());
+
+        Ok(())
+    }
+
+    /// Serializes the configuration to pretty JSON with `bot_token`, `sales_bot_token` and
+    /// `api_key` replaced by `***`, so it can be shared when asking for help.
+    pub fn redacted_json(&self) -> Result<String, String> {
+        let mut config_file = self.clone();
+        let redacted = "***".to_string();
+
+        config_file.bot_token = redacted.clone();
+        config_file.prefix = self.active_prefix();
+
+        if config_file.sales_bot_token.is_some() {
+            config_file.sales_bot_token = Some(redacted.clone());
+        }
+
+        if config_file.api_key.is_some() {
+            config_file.api_key = Some(redacted);
+        }
+
+        serde_json::to_string_pretty(&config_file).map_err(|e| e.to_string())
+    }
+
+    /// Lists settings that load fine but will make some features fail.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings: Vec<String> = Vec::new();
+
+        if self.ports.is_empty() {
+            warnings.push("ports is empty, so links can't be generated".to_string());
+        }
+
+        if self.api_enabled && self.api_key.is_none() {
+            warnings.push("api_enabled is set without an api_key, the API won't start".to_string());
+        }
+
+        if let Some(home_template) = &self.home_template {
+            if !Path::new(home_template).is_dir() {
+                warnings.push(format!("home_template {} isn't a directory", home_template));
+            }
+        }
+
+        if let Some(admin_list_file) = &self.admin_list_file {
+            if !Path::new(admin_list_file).is_file() {
+                warnings.push(format!("admin_list_file {} doesn't exist", admin_list_file));
+            }
+        }
+
+        if let Some(geoip_zone_dir) = &self.geoip_zone_dir {
+            if !Path::new(geoip_zone_dir).is_dir() {
+                warnings.push(format!(
+                    "geoip_zone_dir {} isn't a directory",
+                    geoip_zone_dir
+                ));
+            }
+        }
+
+        warnings
+    }
+
     /// Loads the configuration from the specified file path and returns a `ConfigFile` instance.
     pub fn load() -> Result<ConfigFile, Box<dyn std::error::Error>> {
         let settings = Config::builder()
-            .add_source(config::File::with_name("/etc/userbot.json"))
+            .add_source(config::File::with_name(CONFIG_PATH))
             .build()?;
 
-        Ok(settings.try_deserialize::<ConfigFile>()?)
+        let mut config_file = settings.try_deserialize::<ConfigFile>()?;
+
+        if let Some(date_format) = &config_file.date_format {
+            if StrftimeItems::new(date_format).any(|item| matches!(item, Item::Error)) {
+                return Err(format!("Invalid date_format: {}", date_format).into());
+            }
+        }
+
+        if let Some(len) = config_file.unambiguous_password_length {
+            if len < 8 {
+                return Err("unambiguous_password_length must be at least 8".into());
+            }
+        }
+
+        if let UsernameStrategy::Random(len) = config_file.username_strategy {
+            if len < 4 {
+                return Err("username_strategy random length must be at least 4".into());
+            }
+        }
+
+        if let PasswordHashScheme::Yescrypt = config_file.password_hash_scheme {
+            if !command_in_path("mkpasswd") {
+                return Err(
+                    "password_hash_scheme yescrypt needs mkpasswd, install the whois package"
+                        .into(),
+                );
+            }
+
+            if !command_in_path("perl") {
+                return Err("password_hash_scheme yescrypt needs perl to check passwords".into());
+            }
+        }
+
+        if let Some(timezone) = &config_file.timezone {
+            if timezone.parse::<Tz>().is_err() {
+                return Err(format!("Invalid timezone: {}", timezone).into());
+            }
+        }
+
+        if let Some(welcome_image) = &config_file.welcome_image {
+            let bytes = std::fs::read(welcome_image)
+                .map_err(|e| format!("Couldn't read welcome_image {}: {}", welcome_image, e))?;
+
+            image::load_from_memory(&bytes)
+                .map_err(|e| format!("Invalid welcome_image {}: {}", welcome_image, e))?;
+
+            config_file.welcome_image_bytes = Some(bytes.into());
+        }
+
+        Ok(config_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Maps {
+        #[serde(default, deserialize_with = "deserialize_parsed_keys")]
+        admin_quotas: HashMap<i64, u32>,
+        #[serde(default, deserialize_with = "deserialize_parsed_keys")]
+        error_code_map: HashMap<i32, String>,
+    }
+
+    #[test]
+    fn parsed_keys_read_numeric_keys() {
+        let maps: Maps = serde_json::from_str(
+            r#"{"admin_quotas": {"123456789": 50, "-100": 5}, "error_code_map": {"19": "Locked"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(maps.admin_quotas.get(&123456789), Some(&50));
+        assert_eq!(maps.admin_quotas.get(&-100), Some(&5));
+        assert_eq!(
+            maps.error_code_map.get(&19).map(String::as_str),
+            Some("Locked")
+        );
+    }
+
+    #[test]
+    fn parsed_keys_reject_malformed_keys() {
+        assert!(serde_json::from_str::<Maps>(r#"{"admin_quotas": {"12a": 50}}"#).is_err());
+        assert!(serde_json::from_str::<Maps>(r#"{"error_code_map": {"": "Empty"}}"#).is_err());
+    }
+
+    #[test]
+    fn parsed_keys_default_to_empty() {
+        let maps: Maps = serde_json::from_str("{}").unwrap();
+
+        assert!(maps.admin_quotas.is_empty());
+        assert!(maps.error_code_map.is_empty());
     }
 }
@@ -0,0 +1,171 @@
+use crate::config;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Name of the ipset holding the IP ranges of a country.
+fn set_name(country: &str) -> String {
+    format!("geoblock_{}", country)
+}
+
+/// Checks that a country code is an ISO 3166-1 alpha-2 code, returning it in lowercase.
+pub fn parse_country_code(code: &str) -> Result<String, String> {
+    if code.len() == 2 && code.bytes().all(|b| b.is_ascii_alphabetic()) {
+        Ok(code.to_ascii_lowercase())
+    } else {
+        Err(format!("Invalid country code {}", code))
+    }
+}
+
+/// Lists the blocked countries recorded in `geoblock_file`.
+pub fn blocked_countries() -> Vec<String> {
+    std::fs::read_to_string(&config::CONFIG.geoblock_file)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+fn save_blocked_countries(countries: &[String]) -> Result<(), String> {
+    let path = &config::CONFIG.geoblock_file;
+    let content: String = countries
+        .iter()
+        .map(|country| format!("{}\n", country))
+        .collect();
+
+    // Write a temporary file and rename it, so a failed write can't leave a truncated list.
+    let temp_path = format!("{}.tmp", path);
+    std::fs::write(&temp_path, content)
+        .and_then(|_| std::fs::rename(&temp_path, path))
+        .map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+/// Runs `iptables` with the rule that drops the traffic of a country's ipset.
+fn iptables_rule(action: &str, country: &str) -> Result<bool, String> {
+    Command::new("iptables")
+        .args([action, "INPUT", "-m", "set", "--match-set"])
+        .arg(set_name(country))
+        .args(["src", "-j", "DROP"])
+        .status()
+        .map(|status| status.success())
+        .map_err(|_| "Command iptables not found".to_string())
+}
+
+/// Loads the IP ranges of a country from `<geoip_zone_dir>/<country>.zone` into its ipset, and
+/// drops incoming traffic from it.
+fn apply_block(country: &str) -> Result<(), String> {
+    let zone_dir = config::CONFIG
+        .geoip_zone_dir
+        .as_ref()
+        .ok_or("geoip_zone_dir is not configured".to_string())?;
+    let zone_path = Path::new(zone_dir).join(format!("{}.zone", country));
+    let zone = std::fs::read_to_string(&zone_path)
+        .map_err(|_| format!("Couldn't read IP ranges from {}", zone_path.display()))?;
+
+    let name = set_name(country);
+    let mut restore = format!("create {} hash:net -exist\nflush {}\n", name, name);
+
+    for range in zone
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        restore.push_str(&format!("add {} {} -exist\n", name, range));
+    }
+
+    let mut child = Command::new("ipset")
+        .arg("restore")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|_| "Command ipset not found".to_string())?;
+
+    // Dropping stdin closes it, letting ipset finish.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(restore.as_bytes())
+            .map_err(|e| format!("Couldn't write to ipset: {}", e))?;
+    }
+
+    match child.wait() {
+        Ok(status) if status.success() => {}
+        _ => return Err(format!("Command ipset failed for {}", zone_path.display())),
+    }
+
+    if !iptables_rule("-C", country)? && !iptables_rule("-I", country)? {
+        return Err("Command iptables failed".to_string());
+    }
+
+    Ok(())
+}
+
+/// Blocks incoming traffic from a country and records it in `geoblock_file`.
+///
+/// # Arguments
+///
+/// * `code` - The ISO 3166-1 alpha-2 code of the country.
+///
+/// # Returns
+///
+/// A `Result` containing the lowercase country code if successful, or an error message if the code
+/// is invalid, its IP ranges can't be read, or `ipset`/`iptables` fail or are missing.
+pub fn block_country(code: &str) -> Result<String, String> {
+    let country = parse_country_code(code)?;
+
+    apply_block(&country)?;
+
+    let mut countries = blocked_countries();
+    if !countries.contains(&country) {
+        countries.push(country.clone());
+        save_blocked_countries(&countries)?;
+    }
+
+    Ok(country)
+}
+
+/// Removes the block of a country and its record in `geoblock_file`.
+///
+/// # Arguments
+///
+/// * `code` - The ISO 3166-1 alpha-2 code of the country.
+///
+/// # Returns
+///
+/// A `Result` containing the lowercase country code if successful, or an error message if the code
+/// is invalid, `ipset`/`iptables` are missing or the list can't be written.
+pub fn unblock_country(code: &str) -> Result<String, String> {
+    let country = parse_country_code(code)?;
+
+    // Delete every copy of the rule, in case it was added twice.
+    while iptables_rule("-D", &country)? {}
+
+    // Destroying a set that doesn't exist fails, which is fine since nothing is left to remove.
+    Command::new("ipset")
+        .arg("destroy")
+        .arg(set_name(&country))
+        .status()
+        .map_err(|_| "Command ipset not found".to_string())?;
+
+    let mut countries = blocked_countries();
+    countries.retain(|blocked| blocked != &country);
+    save_blocked_countries(&countries)?;
+
+    Ok(country)
+}
+
+/// Applies the blocks recorded in `geoblock_file` again, since ipsets and iptables rules don't
+/// survive reboots.
+///
+/// # Returns
+///
+/// A list with an error message for each country that couldn't be blocked.
+pub fn restore_blocks() -> Vec<String> {
+    blocked_countries()
+        .iter()
+        .filter_map(|country| {
+            apply_block(country)
+                .err()
+                .map(|err| format!("Couldn't block {}: {}", country, err))
+        })
+        .collect()
+}
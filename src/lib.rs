@@ -1,19 +1,34 @@
+pub mod api;
+pub mod audit;
 pub mod config;
+pub mod geoblock;
+pub mod schedule;
 
 use byteorder::{ByteOrder, LittleEndian};
-use chrono::{Duration, Local, NaiveDate};
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, Utc};
+use chrono_tz::Tz;
+use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
-use image::{load_from_memory, DynamicImage, ImageOutputFormat, Luma, LumaA, Pixel, Rgb};
+use image::{load_from_memory, DynamicImage, ImageOutputFormat, Luma, LumaA, Pixel, Rgb, RgbImage};
 use pwhash::sha512_crypt;
+use qrcode::render::svg;
 use qrcode::QrCode;
 use rand::prelude::*;
 use regex::Regex;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::io::{Read, Write};
-use std::process::Command;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, Once, OnceLock};
+use std::time::Instant;
 use time::{format_description::parse, macros::format_description, Date};
+use users::os::unix::UserExt;
 
+#[derive(Serialize)]
 pub struct UserStatus {
     pub username: String,
     pub status: String,
@@ -31,6 +46,7 @@ impl fmt::Display for UserStatus {
 
 pub struct UserMax {
     pub username: String,
+    pub group: String,
     pub max_logins: String,
 }
 
@@ -38,8 +54,8 @@ impl fmt::Display for UserMax {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             formatter,
-            "username: `{}`\nmax logins: `{}`",
-            self.username, self.max_logins
+            "username: `{}`\ngroup: `{}`\nmax logins: `{}`",
+            self.username, self.group, self.max_logins
         )
     }
 }
@@ -59,6 +75,23 @@ impl fmt::Display for UserPass {
     }
 }
 
+#[derive(Serialize)]
+pub struct UserShell {
+    pub username: String,
+    pub shell: String,
+}
+
+impl fmt::Display for UserShell {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "username: `{}`\nshell: `{}`",
+            self.username, self.shell
+        )
+    }
+}
+
+#[derive(Serialize)]
 pub struct UserExp {
     pub username: String,
     pub exp_date: String,
@@ -69,15 +102,139 @@ impl fmt::Display for UserExp {
         write!(
             formatter,
             "username: {}\nexpiry date: {}",
-            self.username, self.exp_date
+            self.username,
+            display_date(&self.exp_date)
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct UserSessions {
+    pub username: String,
+    pub sessions: usize,
+}
+
+impl fmt::Display for UserSessions {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "username: `{}`\nsessions: `{}`",
+            self.username, self.sessions
+        )
+    }
+}
+
+/// Represents a managed user as stored in exports and backups.
+#[derive(Serialize)]
+pub struct UserRecord {
+    pub username: String,
+    pub group: String,
+    pub expiry_date: String,
+}
+
+#[derive(Serialize)]
+pub struct UserOwner {
+    pub username: String,
+    pub owner: i64,
+}
+
+impl fmt::Display for UserOwner {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "username: `{}`\nowner: `{}`",
+            self.username, self.owner
+        )
+    }
+}
+
+/// Represents the details of an existing user shown by `/info`.
+#[derive(Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub group: String,
+    pub expiry_date: String,
+    pub shell: String,
+    pub status: String,
+    pub rate_mbit: Option<u32>,
+    pub owner: Option<i64>,
+    pub last_login: Option<Option<String>>,
+    pub connection_ips: Option<Vec<String>>,
+}
+
+impl fmt::Display for UserInfo {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let owner = self
+            .owner
+            .map_or("none".to_string(), |owner| owner.to_string());
+        let rate_limit = self
+            .rate_mbit
+            .map_or("none".to_string(), |mbit| format!("{} Mbit/s", mbit));
+        let last_login = match &self.last_login {
+            Some(Some(last_login)) => last_login.as_str(),
+            Some(None) => "never",
+            None => "unavailable",
+        };
+        let connection_ips = match &self.connection_ips {
+            Some(ips) if ips.is_empty() => "none".to_string(),
+            Some(ips) => ips.join(", "),
+            None => "unavailable".to_string(),
+        };
+
+        write!(
+            formatter,
+            "username: `{}`\ngroup: `{}`\nmax logins: `{}`\nexpiry date: `{}`\nshell: `{}`\nstatus: `{}`\nrate limit: `{}`\nowner: `{}`\nlast login: `{}`\nconnection IPs: `{}`",
+            self.username,
+            self.group,
+            max_logins_label(&self.group),
+            display_date(&self.expiry_date),
+            self.shell,
+            self.status,
+            rate_limit,
+            owner,
+            last_login,
+            connection_ips
+        )
+    }
+}
+
+/// Represents the load, memory and disk usage of the server.
+#[derive(Serialize)]
+pub struct ServerResources {
+    pub load_average: [f64; 3],
+    pub memory_total_kb: u64,
+    pub memory_available_kb: u64,
+    pub disk_total_kb: u64,
+    pub disk_available_kb: u64,
+    pub managed_users: usize,
+}
+
+impl fmt::Display for ServerResources {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "load average: `{:.2} {:.2} {:.2}`\nmemory available: `{} MB of {} MB`\ndisk available: `{} MB of {} MB`\nusers: `{}`",
+            self.load_average[0],
+            self.load_average[1],
+            self.load_average[2],
+            self.memory_available_kb / 1024,
+            self.memory_total_kb / 1024,
+            self.disk_available_kb / 1024,
+            self.disk_total_kb / 1024,
+            match config::CONFIG.max_total_users {
+                Some(max_total_users) => format!("{} of {}", self.managed_users, max_total_users),
+                None => self.managed_users.to_string(),
+            },
         )
     }
 }
 
 /// Represents the user information.
+#[derive(Serialize)]
 pub struct SSHUser {
     pub username: String,
     pub password: String,
+    pub group: String,
     pub max_logins: String,
     pub expiry_date: String,
 }
@@ -87,8 +244,12 @@ impl fmt::Display for SSHUser {
     fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             formatter,
-            "username: `{}`\npassword: `{}`\nmax logins: `{}`\nexpiry date: `{}`",
-            self.username, self.password, self.max_logins, self.expiry_date,
+            "username: `{}`\npassword: `{}`\ngroup: `{}`\nmax logins: `{}`\nexpiry date: `{}`",
+            self.username,
+            self.password,
+            self.group,
+            self.max_logins,
+            display_date(&self.expiry_date),
         )
     }
 }
@@ -101,6 +262,7 @@ impl fmt::Display for SSHUser {
 /// * `group` - The user group for the new SSH user.
 /// * `password` - The password for the new SSH user.
 /// * `exp_date` - The expiry date for the new SSH user's account.
+/// * `supplementary_groups` - Additional groups the new SSH user joins, may be empty.
 ///
 /// # Returns
 ///
@@ -110,10 +272,24 @@ pub fn newuser(
     group: &str,
     password: &str,
     exp_date: &str,
+    supplementary_groups: &[String],
 ) -> Result<SSHUser, String> {
+    check_argument(username)?;
     let exp_date = format_exp_date(&exp_date)?;
-    let password_hash = hash_password(password);
-    let process_status = Command::new("useradd")
+    check_server_capacity()?;
+    check_allowed_group(group)?;
+    check_max_logins(group)?;
+
+    for supplementary_group in supplementary_groups {
+        check_argument(supplementary_group)?;
+        if users::get_group_by_name(supplementary_group).is_none() {
+            return Err(format!("Group {} doesn't exist", supplementary_group));
+        }
+    }
+
+    let password_hash = hash_password(password)?;
+    let mut command = sbin_command("useradd");
+    command
         .arg("-p")
         .arg(&password_hash)
         .arg("-s")
@@ -121,25 +297,35 @@ pub fn newuser(
         .arg("-g")
         .arg(&group)
         .arg("-e")
-        .arg(&exp_date)
-        .arg(&username)
-        .status();
+        .arg(with_grace_period(&exp_date))
+        .arg("-c")
+        .arg(expiry_comment(&exp_date));
+
+    if !supplementary_groups.is_empty() {
+        command.arg("-G").arg(supplementary_groups.join(","));
+    }
+
+    let process_status = command.arg(&username).status();
 
     match process_status {
         Ok(status) => {
             if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(SSHUser {
-                    username: username.to_string(),
-                    password: password.to_string(),
-                    max_logins: group.replace("max", "").to_string(),
-                    expiry_date: exp_date.to_string(),
-                })
+                return Err(error);
             }
         }
-        Err(_) => Err("Command useradd not found".to_string()),
+        Err(_) => return Err("Command useradd not found".to_string()),
     }
+
+    // Only write the limits file once the user exists, so a failed creation leaves nothing behind.
+    ensure_maxlogins_limit(group);
+
+    Ok(SSHUser {
+        username: username.to_string(),
+        password: password.to_string(),
+        group: group.to_string(),
+        max_logins: max_logins_label(group),
+        expiry_date: exp_date.to_string(),
+    })
 }
 
 /// Automatically generates a new SSH user based on certain parameters.
@@ -155,249 +341,3039 @@ pub fn newuser(
 /// A `Result` containing the automatically generated `SSHUser` if successful, or an error message if
 /// the user creation fails.
 pub fn auto_newuser(prefix: &str, group: &str, days: i64) -> Result<SSHUser, String> {
-    let password = gen_password();
+    let password = new_password();
 
-    let users_count = get_users_core(prefix, None).len();
-
-    let username = format!("{}{:03}",prefix, users_count + 1);
     let exp_date = add_to_time(days + 1);
+    let mut retries = 0;
 
-    newuser(&username, group, &password, &exp_date)
-}
-
-pub fn unlock_user(username: &str) -> Result<UserStatus, String> {
-    let process_status = Command::new("usermod").arg(username).arg("-U").status();
+    // Another user may take the picked name before useradd runs, so pick again when that happens.
+    loop {
+        let username = generate_username(prefix)?;
 
-    match process_status {
-        Ok(status) => {
-            if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(UserStatus {
-                    username: username.to_string(),
-                    status: "Unlocked".to_string(),
-                })
+        match newuser(&username, group, &password, &exp_date, &[]) {
+            Err(_) if user_exists(&username) && retries < config::CONFIG.collision_retry_limit => {
+                retries += 1;
             }
+            result => return result,
         }
-        Err(_) => Err("Command chage not found".to_string()),
     }
 }
 
-pub fn userdel(username: &str) -> Result<UserStatus, String> {
-    let process_status = Command::new("userdel").arg(username).status();
+/// Finds the next free numbered variant of a username, like `alice2` for `alice` or `user008`
+/// for `user007`, trying at most `collision_retry_limit` names.
+pub fn next_free_username(username: &str) -> Option<String> {
+    let base = username.trim_end_matches(|c: char| c.is_ascii_digit());
+    let digits = &username[base.len()..];
 
-    match process_status {
-        Ok(status) => {
-            if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(UserStatus {
-                    username: username.to_string(),
-                    status: "Deleted".to_string(),
-                })
-            }
-        }
-        Err(_) => Err("Command usermod not found".to_string()),
-    }
-}
+    let (start, width) = if digits.is_empty() {
+        (2, 0)
+    } else {
+        (digits.parse::<u64>().ok()? + 1, digits.len())
+    };
 
-pub fn change_max(username: &str, group: &str) -> Result<UserMax, String> {
-    let process_status = Command::new("usermod")
-        .arg(username)
-        .arg("-g")
-        .arg(&group)
-        .status();
+    (start..start + u64::from(config::CONFIG.collision_retry_limit))
+        .map(|number| format!("{}{:0width$}", base, number, width = width))
+        .find(|candidate| !user_exists(candidate))
+}
 
-    match process_status {
-        Ok(status) => {
-            if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(UserMax {
-                    username: username.to_string(),
-                    max_logins: group.replace("max", ""),
-                })
-            }
-        }
-        Err(_) => Err("Command usermod not found".to_string()),
+/// Creates a user with `create`, handling a taken username according to `auto_retry_on_collision`:
+/// the next free numbered variant is either created instead, or suggested in the error message.
+///
+/// # Arguments
+///
+/// * `username` - The username asked for.
+/// * `create` - Creates the user with the given username.
+///
+/// # Returns
+///
+/// A `Result` containing the new `SSHUser`, whose username differs from the one asked for if a
+/// variant was created, or an error message if the user creation fails.
+pub fn create_or_retry(
+    username: &str,
+    create: impl Fn(&str) -> Result<SSHUser, String>,
+) -> Result<SSHUser, String> {
+    match create(username) {
+        Err(err) if user_exists(username) => match next_free_username(username) {
+            Some(variant) if config::CONFIG.auto_retry_on_collision => create(&variant),
+            Some(variant) => Err(format!("{}, {} is free", err, variant)),
+            None => Err(err),
+        },
+        result => result,
     }
 }
 
-pub fn change_pass(username: &str, password: &str) -> Result<UserPass, String> {
-    let password_hash = hash_password(password);
-    let process_status = Command::new("usermod")
-        .arg(username)
-        .arg("-p")
-        .arg(&password_hash)
-        .status();
+/// Characters of random username suffixes.
+const USERNAME_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
 
-    match process_status {
-        Ok(status) => {
-            if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(UserPass {
-                    username: username.to_string(),
-                    password: password.to_string(),
-                })
-            }
+/// Picks the username of a new user according to `username_strategy`.
+///
+/// # Returns
+///
+/// A `Result` containing the username, or an error message if no unused random username was found.
+fn generate_username(prefix: &str) -> Result<String, String> {
+    match config::CONFIG.username_strategy {
+        config::UsernameStrategy::Sequential => {
+            Ok(format!("{}{:03}", prefix, max_user_suffix(prefix) + 1))
         }
-        Err(_) => Err("Command usermod not found".to_string()),
-    }
-}
+        config::UsernameStrategy::Random(len) => {
+            let mut rng = rand::thread_rng();
 
-pub fn lock_user(username: &str) -> Result<UserStatus, String> {
-    let process_status = Command::new("usermod").arg(username).arg("-L").status();
+            // Collisions are unlikely, so a few attempts are plenty.
+            for _ in 0..10 {
+                let suffix: String = (0..len)
+                    .map(|_| *USERNAME_ALPHABET.choose(&mut rng).unwrap() as char)
+                    .collect();
+                let username = format!("{}{}", prefix, suffix);
 
-    match process_status {
-        Ok(status) => {
-            if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(UserStatus {
-                    username: username.to_string(),
-                    status: "Locked".to_string(),
-                })
+                if !user_exists(&username) {
+                    return Ok(username);
+                }
             }
+
+            Err("Couldn't find an unused random username".to_string())
+        }
+        config::UsernameStrategy::DatePrefixed => {
+            let date_prefix = format!("{}{}", prefix, today().format("%y%m%d"));
+
+            Ok(format!(
+                "{}{:02}",
+                date_prefix,
+                max_user_suffix(&date_prefix) + 1
+            ))
         }
-        Err(_) => Err("Command usermod not found".to_string()),
     }
 }
 
-pub fn change_exp(username: &str, exp_date: &str) -> Result<UserExp, String> {
-    let exp_date = format_exp_date(&exp_date)?;
+/// Creates a new user with the group, expiry date and login shell of an existing one.
+///
+/// The new user always gets a freshly generated password, never the source's.
+///
+/// # Arguments
+///
+/// * `source` - The username of the user to copy the settings of.
+/// * `username` - The username of the new SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the new `SSHUser` if successful, or an error message if the source can't
+/// be read or the new user can't be created.
+pub fn clone_user(source: &str, username: &str) -> Result<SSHUser, String> {
+    let source_info = get_user_info(source)?;
 
-    let process_status = Command::new("chage")
-        .arg(username)
-        .arg("-E")
-        .arg(&exp_date)
-        .status();
+    // Check the shell before creating anything, so a clone never ends up with a different one.
+    if source_info.shell != "/bin/rbash"
+        && !valid_shells()?
+            .iter()
+            .any(|valid_shell| valid_shell == &source_info.shell)
+    {
+        return Err(format!(
+            "Shell {} of {} is not listed in /etc/shells",
+            source_info.shell, source
+        ));
+    }
 
-    match process_status {
-        Ok(status) => {
-            if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(UserExp {
-                    username: username.to_string(),
-                    exp_date: exp_date,
-                })
-            }
+    let sshuser = newuser(
+        username,
+        &source_info.group,
+        &new_password(),
+        &source_info.expiry_date,
+        &[],
+    )?;
+
+    if source_info.shell != "/bin/rbash" {
+        if let Err(err) = change_shell(username, &source_info.shell) {
+            log::warn!("Couldn't set shell of {}: {}", username, err);
         }
-        Err(_) => Err("Command chage not found".to_string()),
     }
-}
 
-pub fn renew_user(username: &str, days: i64) -> Result<UserExp, String> {
-    let exp_date = add_to_time(days + 1);
+    Ok(sshuser)
+}
 
-    let process_status = Command::new("chage")
-        .arg(username)
-        .arg("-E")
-        .arg(&exp_date)
-        .status();
+/// Creates a new user with a generated password that expires at a precise time instead of at the
+/// start of a day.
+///
+/// `chage` only works with whole days, so the system expiry date is set to the day after
+/// `expires_at`, and the time is recorded as `expires_at=<unix timestamp>` in the comment field for
+/// `lock_precise_expired_users` to lock the user on time.
+///
+/// # Arguments
+///
+/// * `username` - The username of the new SSH user.
+/// * `group` - The user group for the new SSH user.
+/// * `expires_at` - The expiry time in RFC 3339 format, e.g. `2024-01-31T18:00:00+00:00`.
+///
+/// # Returns
+///
+/// A `Result` containing the new `SSHUser` if successful, or an error message if the time is
+/// invalid or in the past, or the user can't be created.
+pub fn newuser_precise(username: &str, group: &str, expires_at: &str) -> Result<SSHUser, String> {
+    let expires_at = DateTime::parse_from_rfc3339(expires_at)
+        .map_err(|_| "Invalid expiry time, use e.g. 2024-01-31T18:00:00+00:00".to_string())?
+        .with_timezone(&Utc);
 
-    match process_status {
-        Ok(status) => {
-            if let Some(error) = unixuser_code_to_err(status.code()) {
-                Err(error)
-            } else {
-                Ok(UserExp {
-                    username: username.to_string(),
-                    exp_date: exp_date,
-                })
-            }
-        }
-        Err(_) => Err("Command chage not found".to_string()),
+    if expires_at <= Utc::now() {
+        return Err("Expiry time is in the past".to_string());
     }
-}
 
-pub fn get_chage_exp(username: &str) -> Result<UserExp, String> {
-    let process_output = Command::new("chage").arg("-l").arg(username).output();
-    match process_output {
-        Ok(output) => {
-            if let Some(error) = unixuser_code_to_err(output.status.code()) {
-                Err(error)
-            } else {
-                let user_info = String::from_utf8(output.stdout).unwrap();
+    let exp_date = (local_date(expires_at) + Duration::days(1))
+        .format("%Y-%m-%d")
+        .to_string();
 
-                let re = Regex::new("Account expires\t+: (.*)\n").unwrap();
+    let sshuser = newuser(username, group, &new_password(), &exp_date, &[])?;
 
-                let caps = re.captures(&user_info);
+    set_comment_field(username, "expires_at", &expires_at.timestamp().to_string()).map_err(
+        |err| {
+            format!(
+                "Created {} but couldn't record its expiry time, it expires on {}: {}",
+                username, exp_date, err
+            )
+        },
+    )?;
 
-                if let Some(caps) = caps {
-                    let exp_date = caps.get(1).map_or("", |m| m.as_str()).to_string();
-                    if &exp_date == "never" {
-                        return Ok(UserExp {
-                            username: username.to_string(),
-                            exp_date: "never".to_string(),
-                        });
-                    }
+    Ok(sshuser)
+}
 
-                    let inp_format = format_description!("[month repr:short] [day], [year]");
+/// Locks the users whose `expires_at` time has passed and removes the field, so each is only
+/// locked once. They're locked like the expiry sweep does, so renewing them unlocks them.
+///
+/// # Returns
+///
+/// A list with the result of locking each user.
+pub fn lock_precise_expired_users() -> Vec<Result<UserStatus, String>> {
+    let now = Utc::now().timestamp();
 
-                    let out_format = format_description!("[year]-[month]-[day]");
-                    match Date::parse(&exp_date, &inp_format) {
-                        Ok(date) => Ok(UserExp {
-                            username: username.to_string(),
-                            exp_date: date.format(&out_format).unwrap(),
-                        }),
-                        Err(_) => Err("Invalid Expiry date".to_string()),
-                    }
-                } else {
-                    Err("Unexpected error".to_string())
-                }
-            }
-        }
-        Err(_e) => Err("Command chage not found".to_string()),
-    }
+    let due_users: Vec<String> = users_with_comment_field("expires_at")
+        .into_iter()
+        .filter_map(|(username, expires_at)| {
+            (expires_at.parse::<i64>().ok()? <= now).then_some(username)
+        })
+        .collect();
+
+    due_users
+        .iter()
+        .map(|username| {
+            let user_status = lock_expired_user(username)
+                .map_err(|err| format!("Couldn't lock {}: {}", username, err))?;
+            remove_comment_field(username, "expires_at")
+                .map_err(|err| format!("Couldn't clear expiry time of {}: {}", username, err))?;
+
+            Ok(user_status)
+        })
+        .collect()
 }
 
-fn unixuser_code_to_err(code: Option<i32>) -> Option<String> {
-    if let Some(code) = code {
-        match code {
-            0 => None,
+/// Creates a new user whose expiry date is only set once it first logs in, to `days` days after
+/// that login.
+///
+/// Until then, the user is pending activation with `activate_days=<days>` and
+/// `pending_since=<unix timestamp>` in its comment field, and expires `activation_window_days`
+/// from now so accounts that are never used still expire. `activate_logged_in_users` sets the real
+/// expiry date.
+///
+/// # Arguments
+///
+/// * `username` - The username of the new SSH user.
+/// * `group` - The user group for the new SSH user.
+/// * `password` - The password for the new SSH user.
+/// * `days` - The number of days the account lasts after its first login.
+///
+/// # Returns
+///
+/// A `Result` containing the new `SSHUser` if successful, or an error message if the user can't be
+/// created.
+pub fn newuser_on_login(
+    username: &str,
+    group: &str,
+    password: &str,
+    days: i64,
+) -> Result<SSHUser, String> {
+    let exp_date = add_to_time(config::CONFIG.activation_window_days + 1);
+
+    let sshuser = newuser(username, group, password, &exp_date, &[])?;
+
+    set_comment_field(
+        username,
+        "pending_since",
+        &Utc::now().timestamp().to_string(),
+    )
+    .and_then(|_| set_comment_field(username, "activate_days", &days.to_string()))
+    .map_err(|err| {
+        format!(
+            "Created {} but couldn't mark it pending activation, it expires on {}: {}",
+            username, exp_date, err
+        )
+    })?;
+
+    Ok(sshuser)
+}
+
+/// Sets the expiry date of the users pending activation that logged in, to `activate_days` days
+/// after their login.
+///
+/// Logins from before `pending_since` are ignored, since `lastlog` goes by UID and may still show
+/// the login of a deleted user that had the same UID.
+///
+/// # Returns
+///
+/// A list with the result of activating each user that logged in.
+pub fn activate_logged_in_users() -> Vec<Result<UserExp, String>> {
+    users_with_comment_field("activate_days")
+        .into_iter()
+        .filter_map(|(username, days)| {
+            let login = match last_login(&username) {
+                Ok(login) => login?,
+                Err(err) => return Some(Err(format!("Couldn't check {}: {}", username, err))),
+            };
+
+            let pending_since = get_comment_field(&username, "pending_since")
+                .and_then(|since| since.parse::<i64>().ok())
+                .unwrap_or_default();
+            if login.time.timestamp() < pending_since {
+                return None;
+            }
+
+            let result = days
+                .parse::<i64>()
+                .map_err(|_| format!("Invalid activate_days of {}", username))
+                .and_then(|days| {
+                    let login_date = local_date(login.time.with_timezone(&Utc));
+                    let exp_date = (login_date + Duration::days(days + 1))
+                        .format("%Y-%m-%d")
+                        .to_string();
+
+                    change_exp(&username, &exp_date)
+                })
+                .map_err(|err| format!("Couldn't activate {}: {}", username, err));
+
+            Some(result)
+        })
+        .collect()
+}
+
+/// Represents a reminder that a user expires soon.
+pub struct ExpiryReminder {
+    pub username: String,
+    pub exp_date: String,
+    pub days_left: i64,
+}
+
+impl fmt::Display for ExpiryReminder {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "User {} expires on {}, in {} days",
+            self.username,
+            display_date(&self.exp_date),
+            self.days_left
+        )
+    }
+}
+
+/// Sets the days before its expiry date a user's owner is reminded on, recorded as
+/// `reminders=7;3;1` in the user's comment field.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `offsets` - Comma-separated days before the expiry date, like `7,3,1`, or `off` to remove the
+///   reminders.
+///
+/// # Returns
+///
+/// A `Result` containing the offsets, largest first, or an error message if the user doesn't
+/// exist, an offset is invalid or the comment can't be changed.
+pub fn set_reminders(username: &str, offsets: &str) -> Result<Vec<i64>, String> {
+    check_argument(username)?;
+
+    if !user_exists(username) {
+        return Err(format!("User {} not found", username));
+    }
+
+    if offsets == "off" {
+        remove_comment_field(username, "reminders")?;
+        remove_comment_field(username, "reminded")?;
+
+        return Ok(Vec::new());
+    }
+
+    let mut offsets = offsets
+        .split(',')
+        .map(|offset| {
+            let offset = offset
+                .trim()
+                .parse::<i64>()
+                .map_err(|_| format!("Invalid reminder offset {}", offset))?;
+            validate_days(offset, config::CONFIG.max_expiry_days)?;
+
+            Ok(offset)
+        })
+        .collect::<Result<Vec<i64>, String>>()?;
+
+    offsets.sort_unstable_by(|a, b| b.cmp(a));
+    offsets.dedup();
+
+    let value: Vec<String> = offsets.iter().map(|offset| offset.to_string()).collect();
+    set_comment_field(username, "reminders", &value.join(";"))?;
+    remove_comment_field(username, "reminded")?;
+
+    Ok(offsets)
+}
+
+/// Finds the users with `reminders` whose expiry date is within one of their offsets that wasn't
+/// reminded of yet, and records those offsets as `reminded=<expiry date>;<offsets>` in their
+/// comment field so they're reminded once. A new expiry date re-arms every offset.
+///
+/// Offsets missed while the bot was down are merged into a single reminder.
+///
+/// # Returns
+///
+/// A list with a reminder, or the error recording it, for each user that is due one.
+pub fn take_due_reminders() -> Vec<Result<ExpiryReminder, String>> {
+    let today = today();
+
+    users_with_comment_field("reminders")
+        .into_iter()
+        .filter_map(|(username, offsets)| {
+            let exp_date = get_expiry_date(&username)?;
+            let days_left = (exp_date - today).num_days();
+
+            if days_left < 0 {
+                return None;
+            }
+
+            let exp_date = exp_date.format("%Y-%m-%d").to_string();
+            let mut reminded: Vec<i64> = get_comment_field(&username, "reminded")
+                .and_then(|reminded| {
+                    let mut parts = reminded.split(';');
+                    (parts.next() == Some(exp_date.as_str()))
+                        .then(|| parts.filter_map(|offset| offset.parse().ok()).collect())
+                })
+                .unwrap_or_default();
+
+            let due: Vec<i64> = offsets
+                .split(';')
+                .filter_map(|offset| offset.parse::<i64>().ok())
+                .filter(|offset| days_left <= *offset && !reminded.contains(offset))
+                .collect();
+
+            if due.is_empty() {
+                return None;
+            }
+
+            reminded.extend(due);
+            let value: Vec<String> = std::iter::once(exp_date.clone())
+                .chain(reminded.iter().map(|offset| offset.to_string()))
+                .collect();
+
+            let result = set_comment_field(&username, "reminded", &value.join(";"))
+                .map(|_| ExpiryReminder {
+                    username: username.clone(),
+                    exp_date,
+                    days_left,
+                })
+                .map_err(|err| format!("Couldn't record reminder of {}: {}", username, err));
+
+            Some(result)
+        })
+        .collect()
+}
+
+/// Lists the users with a `key=value` field in their comment, with its value, reading
+/// `/etc/passwd` once.
+fn users_with_comment_field(key: &str) -> Vec<(String, String)> {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let value = find_comment_field(fields.get(4)?, key)?;
+
+            Some((fields[0].to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Drops the `expires_at` time and pending activation of a user once it's given a new expiry date.
+fn clear_scheduled_expiry(username: &str) {
+    for key in ["expires_at", "activate_days", "pending_since"] {
+        if get_comment_field(username, key).is_none() {
+            continue;
+        }
+
+        if let Err(err) = remove_comment_field(username, key) {
+            log::warn!("Couldn't clear {} of {}: {}", key, username, err);
+        }
+    }
+}
+
+/// Shells that can't run an interactive password change at login.
+const NON_INTERACTIVE_SHELLS: [&str; 5] = [
+    "/bin/false",
+    "/usr/bin/false",
+    "/sbin/nologin",
+    "/usr/sbin/nologin",
+    "/bin/true",
+];
+
+/// Forces a user to change their password at the next login, using `chage -d 0`.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// An error message if the user's shell can't change passwords interactively, in which case
+/// nothing is changed, or if `chage` fails.
+pub fn require_password_change(username: &str) -> Result<(), String> {
+    let shell = read_passwd_entry(username)
+        .and_then(|fields| fields.get(6).cloned())
+        .ok_or("User not found")?;
+
+    if NON_INTERACTIVE_SHELLS.contains(&shell.as_str()) {
+        return Err(format!(
+            "Shell {} can't change passwords, not forcing a password change",
+            shell
+        ));
+    }
+
+    let process_status = sbin_command("chage")
+        .arg("-d")
+        .arg("0")
+        .arg(username)
+        .status();
+
+    match process_status {
+        Ok(status) => match unixuser_code_to_err(status.code()) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        },
+        Err(_) => Err("Command chage not found".to_string()),
+    }
+}
+
+/// Installs a template file into the home directory of a user, owned by that user.
+///
+/// The `{username}` and `{server}` placeholders in the template are replaced, and the file keeps
+/// the template's file name (e.g. `.profile`).
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `server_address` - The address substituted for `{server}`.
+/// * `template_path` - The path of the template file.
+///
+/// # Returns
+///
+/// A `Result` containing the path of the installed file, or an error message if installing fails.
+pub fn install_home_template(
+    username: &str,
+    server_address: &str,
+    template_path: &str,
+) -> Result<PathBuf, String> {
+    let user = users::get_user_by_name(username).ok_or("Invalid user or group".to_string())?;
+    let template = std::fs::read_to_string(template_path)
+        .map_err(|_| format!("Couldn't read template {}", template_path))?;
+    let file_name = Path::new(template_path)
+        .file_name()
+        .ok_or_else(|| format!("Invalid template path {}", template_path))?;
+
+    let uid = Some(user.uid());
+    let gid = Some(user.primary_group_id());
+
+    let home_dir = user.home_dir();
+    if !home_dir.exists() {
+        std::fs::create_dir_all(home_dir).map_err(|e| e.to_string())?;
+        chown(home_dir, uid, gid).map_err(|e| e.to_string())?;
+    }
+
+    let content = template
+        .replace("{username}", username)
+        .replace("{server}", server_address);
+
+    let destination = home_dir.join(file_name);
+    std::fs::write(&destination, content).map_err(|e| e.to_string())?;
+    chown(&destination, uid, gid).map_err(|e| e.to_string())?;
+
+    Ok(destination)
+}
+
+pub fn unlock_user(username: &str) -> Result<UserStatus, String> {
+    check_argument(username)?;
+    if is_user_locked(username) == Some(false) {
+        return Ok(UserStatus {
+            username: username.to_string(),
+            status: "Already unlocked".to_string(),
+        });
+    }
+
+    let process_status = sbin_command("usermod").arg(username).arg("-U").status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                Ok(UserStatus {
+                    username: username.to_string(),
+                    status: "Unlocked".to_string(),
+                })
+            }
+        }
+        Err(_) => Err("Command chage not found".to_string()),
+    }
+}
+
+/// Deletes a user.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `force` - Whether to delete the user even if it isn't managed by the bot.
+///
+/// # Returns
+///
+/// A `Result` containing the `UserStatus` if successful, or an error message if the user isn't
+/// managed and `force` isn't set, or the deletion fails.
+pub fn userdel(username: &str, force: bool) -> Result<UserStatus, String> {
+    check_argument(username)?;
+    if !force && !is_managed_user(username) {
+        return Err(format!("Refusing to delete unmanaged user {}", username));
+    }
+
+    // The rules are keyed by UID, so they must go before the user does or a later user with the
+    // same UID would inherit its rate limit.
+    if let (Some(interface), Some(_)) = (
+        &config::CONFIG.rate_limit_interface,
+        get_comment_field(username, "rate_mbit"),
+    ) {
+        if let Some(user) = users::get_user_by_name(username) {
+            if let Err(err) = remove_rate_limit_rules(interface, user.uid()) {
+                log::warn!("Couldn't remove rate limit of {}: {}", username, err);
+            }
+        }
+    }
+
+    let process_status = sbin_command("userdel").arg(username).status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                Ok(UserStatus {
+                    username: username.to_string(),
+                    status: "Deleted".to_string(),
+                })
+            }
+        }
+        Err(_) => Err("Command usermod not found".to_string()),
+    }
+}
+
+pub fn change_max(username: &str, group: &str) -> Result<UserMax, String> {
+    check_argument(username)?;
+    check_allowed_group(group)?;
+    check_max_logins(group)?;
+    set_primary_group(username, group)?;
+    ensure_maxlogins_limit(group);
+
+    Ok(UserMax {
+        username: username.to_string(),
+        group: group.to_string(),
+        max_logins: max_logins_label(group),
+    })
+}
+
+fn set_primary_group(username: &str, group: &str) -> Result<(), String> {
+    let process_status = sbin_command("usermod")
+        .arg(username)
+        .arg("-g")
+        .arg(&group)
+        .status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                Ok(())
+            }
+        }
+        Err(_) => Err("Command usermod not found".to_string()),
+    }
+}
+
+/// Moves a user to the configured `quarantine_group` and locks it, as a reversible alternative to
+/// deletion.
+///
+/// The previous group is recorded in the comment field so `release_user` can restore it.
+pub fn quarantine_user(username: &str) -> Result<UserStatus, String> {
+    let quarantine_group = config::CONFIG
+        .quarantine_group
+        .as_ref()
+        .ok_or("quarantine_group is not configured".to_string())?;
+    let group = get_primary_group(username).ok_or("Invalid user or group".to_string())?;
+
+    if &group == quarantine_group {
+        return Err("User is already quarantined".to_string());
+    }
+
+    set_comment_field(username, "quarantined_from", &group)?;
+    set_primary_group(username, quarantine_group)?;
+    lock_user(username)?;
+
+    Ok(UserStatus {
+        username: username.to_string(),
+        status: "Quarantined".to_string(),
+    })
+}
+
+/// Moves a quarantined user back to its previous group and unlocks it.
+pub fn release_user(username: &str) -> Result<UserStatus, String> {
+    let group = get_comment_field(username, "quarantined_from")
+        .ok_or("User is not quarantined".to_string())?;
+
+    set_primary_group(username, &group)?;
+    unlock_user(username)?;
+    remove_comment_field(username, "quarantined_from")?;
+
+    Ok(UserStatus {
+        username: username.to_string(),
+        status: "Released".to_string(),
+    })
+}
+
+/// Freezes a user's expiry, recording its remaining days as `frozen_days=` in its comment field
+/// and locking it until it's unfrozen.
+pub fn freeze_user(username: &str) -> Result<UserStatus, String> {
+    if get_comment_field(username, "frozen_days").is_some() {
+        return Err("User is already frozen".to_string());
+    }
+
+    let expiry_date = get_expiry_date(username).ok_or("User never expires".to_string())?;
+    let remaining_days = (expiry_date - today()).num_days().max(0);
+
+    set_comment_field(username, "frozen_days", &remaining_days.to_string())?;
+    lock_user(username)?;
+
+    Ok(UserStatus {
+        username: username.to_string(),
+        status: format!("Frozen with {} days left", remaining_days),
+    })
+}
+
+/// Unfreezes a user, moving its expiry date to its remaining days from today and unlocking it.
+pub fn unfreeze_user(username: &str) -> Result<UserExp, String> {
+    let remaining_days: i64 = get_comment_field(username, "frozen_days")
+        .ok_or("User is not frozen".to_string())?
+        .parse()
+        .map_err(|_| "Invalid frozen_days in comment".to_string())?;
+
+    let exp_date = (today() + Duration::days(remaining_days))
+        .format("%Y-%m-%d")
+        .to_string();
+
+    let user_exp = change_exp(username, &exp_date)?;
+    unlock_user(username)?;
+    remove_comment_field(username, "frozen_days")?;
+
+    Ok(user_exp)
+}
+
+pub fn change_pass(username: &str, password: &str) -> Result<UserPass, String> {
+    check_argument(username)?;
+    let password_hash = hash_password(password)?;
+    let process_status = sbin_command("usermod")
+        .arg(username)
+        .arg("-p")
+        .arg(&password_hash)
+        .status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                Ok(UserPass {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+        }
+        Err(_) => Err("Command usermod not found".to_string()),
+    }
+}
+
+/// Reads the login shells listed in `/etc/shells`.
+fn valid_shells() -> Result<Vec<String>, String> {
+    let shells = std::fs::read_to_string("/etc/shells").map_err(|_| "Couldn't read /etc/shells")?;
+
+    Ok(shells
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Changes the login shell of a user.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `shell` - The new login shell, which must be listed in `/etc/shells`.
+///
+/// # Returns
+///
+/// A `Result` containing the `UserShell` if successful, or an error message if the shell isn't
+/// allowed or `usermod` fails.
+pub fn change_shell(username: &str, shell: &str) -> Result<UserShell, String> {
+    check_argument(username)?;
+    if !valid_shells()?
+        .iter()
+        .any(|valid_shell| valid_shell == shell)
+    {
+        return Err(format!("Shell {} is not listed in /etc/shells", shell));
+    }
+
+    let process_status = sbin_command("usermod")
+        .arg("-s")
+        .arg(shell)
+        .arg(username)
+        .status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                Ok(UserShell {
+                    username: username.to_string(),
+                    shell: shell.to_string(),
+                })
+            }
+        }
+        Err(_) => Err("Command usermod not found".to_string()),
+    }
+}
+
+/// Returns a warning if a login shell may get in the way of SSH tunneling.
+pub fn shell_tunnel_warning(shell: &str) -> Option<&'static str> {
+    if shell.ends_with("/nologin") {
+        Some("nologin closes sessions that request a TTY, so clients must tunnel with ssh -N or sshd needs PermitTTY no for these users")
+    } else if NON_INTERACTIVE_SHELLS.contains(&shell) {
+        Some("This shell exits immediately, so tunneling only works with ssh -N and AllowTcpForwarding yes")
+    } else {
+        None
+    }
+}
+
+pub fn lock_user(username: &str) -> Result<UserStatus, String> {
+    lock_user_with_reason(username, None)
+}
+
+/// Locks a user, recording `reason` as its `lock_reason` comment field. The reason is only written
+/// when this call locked the account, so an account an admin already locked keeps surviving
+/// renewals.
+fn lock_user_with_reason(username: &str, reason: Option<&str>) -> Result<UserStatus, String> {
+    check_argument(username)?;
+    let was_locked = is_user_locked(username);
+
+    // Any lock other than the expiry sweep's must survive renewals, so drop a stale reason.
+    if reason.is_none() && get_comment_field(username, "lock_reason").is_some() {
+        remove_comment_field(username, "lock_reason")?;
+    }
+
+    if was_locked == Some(true) {
+        return Ok(UserStatus {
+            username: username.to_string(),
+            status: "Already locked".to_string(),
+        });
+    }
+
+    let process_status = sbin_command("usermod").arg(username).arg("-L").status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                return Err(error);
+            }
+        }
+        Err(_) => return Err("Command usermod not found".to_string()),
+    }
+
+    if let (Some(reason), Some(false)) = (reason, was_locked) {
+        set_comment_field(username, "lock_reason", reason)?;
+    }
+
+    Ok(UserStatus {
+        username: username.to_string(),
+        status: "Locked".to_string(),
+    })
+}
+
+/// Locks a user whose account expired, recording `lock_reason=expired` in its comment field so it
+/// can be told apart from users locked by an admin.
+pub fn lock_expired_user(username: &str) -> Result<UserStatus, String> {
+    lock_user_with_reason(username, Some("expired"))
+}
+
+/// Locks a user targeted by too many failed logins, recording `lock_reason=auth_failures` in its
+/// comment field.
+pub fn lock_brute_forced_user(username: &str) -> Result<UserStatus, String> {
+    lock_user_with_reason(username, Some("auth_failures"))
+}
+
+/// Follows `auth_log_path` and extracts the users of failed SSH logins appended to it.
+pub struct AuthLogWatcher {
+    offset: u64,
+    failure_re: Regex,
+}
+
+impl AuthLogWatcher {
+    /// Starts following the auth log from its current end, so earlier failures are ignored.
+    pub fn new() -> Self {
+        let offset = std::fs::metadata(&config::CONFIG.auth_log_path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        Self {
+            offset,
+            failure_re: Regex::new(
+                r"Failed (?:password|publickey|keyboard-interactive/pam) for (\S+) from ",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Reads the lines appended since the last call and returns the username of every failed
+    /// login of an existing user, once per failure. Failures for invalid users are skipped.
+    ///
+    /// A log shorter than the last read offset was rotated and is read from the start.
+    pub fn read_failures(&mut self) -> Result<Vec<String>, String> {
+        let mut file = std::fs::File::open(&config::CONFIG.auth_log_path)
+            .map_err(|e| format!("Couldn't open {}: {}", config::CONFIG.auth_log_path, e))?;
+        let len = file.metadata().map_err(|e| e.to_string())?.len();
+
+        if len < self.offset {
+            self.offset = 0;
+        }
+
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(self.offset))
+            .and_then(|_| file.read_to_end(&mut bytes))
+            .map_err(|e| format!("Couldn't read {}: {}", config::CONFIG.auth_log_path, e))?;
+
+        // Leave a partially written last line for the next read.
+        let Some(end) = bytes.iter().rposition(|&byte| byte == b'\n') else {
+            return Ok(Vec::new());
+        };
+        self.offset += end as u64 + 1;
+
+        Ok(String::from_utf8_lossy(&bytes[..end])
+            .lines()
+            .filter_map(|line| self.failure_re.captures(line))
+            .map(|captures| captures[1].to_string())
+            .collect())
+    }
+}
+
+impl Default for AuthLogWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unlocks a user locked by the expiry sweep once its new expiry date is in the future. Users
+/// locked by an admin stay locked.
+fn unlock_if_renewed(username: &str, exp_date: &str) {
+    if get_comment_field(username, "lock_reason").as_deref() != Some("expired") {
+        return;
+    }
+
+    match NaiveDate::parse_from_str(exp_date, "%Y-%m-%d") {
+        Ok(date) if date > today() => {}
+        _ => return,
+    }
+
+    if let Err(err) =
+        unlock_user(username).and_then(|_| remove_comment_field(username, "lock_reason"))
+    {
+        log::warn!("Couldn't unlock renewed user {}: {}", username, err);
+    }
+}
+
+pub fn change_exp(username: &str, exp_date: &str) -> Result<UserExp, String> {
+    check_argument(username)?;
+    let exp_date = format_exp_date(&exp_date)?;
+
+    let process_status = sbin_command("chage")
+        .arg(username)
+        .arg("-E")
+        .arg(with_grace_period(&exp_date))
+        .status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                set_comment_expiry(username, &exp_date);
+                unlock_if_renewed(username, &exp_date);
+                clear_scheduled_expiry(username);
+
+                Ok(UserExp {
+                    username: username.to_string(),
+                    exp_date: exp_date,
+                })
+            }
+        }
+        Err(_) => Err("Command chage not found".to_string()),
+    }
+}
+
+pub fn renew_user(username: &str, days: i64) -> Result<UserExp, String> {
+    check_argument(username)?;
+    let exp_date = add_to_time(days + 1);
+
+    let process_status = sbin_command("chage")
+        .arg(username)
+        .arg("-E")
+        .arg(with_grace_period(&exp_date))
+        .status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                set_comment_expiry(username, &exp_date);
+                unlock_if_renewed(username, &exp_date);
+                clear_scheduled_expiry(username);
+
+                Ok(UserExp {
+                    username: username.to_string(),
+                    exp_date: exp_date,
+                })
+            }
+        }
+        Err(_) => Err("Command chage not found".to_string()),
+    }
+}
+
+/// Extends the expiry date of a user by a number of days, counted from its current expiry date
+/// instead of today, so the days it has left aren't lost.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `days` - The number of days to add.
+///
+/// # Returns
+///
+/// A `Result` containing the new `UserExp` if successful, or an error message if the user never
+/// expires or the expiry date can't be read or changed. Users that already expired are extended
+/// from today, like `renew_user` does.
+pub fn extend_user(username: &str, days: i64) -> Result<UserExp, String> {
+    let current_exp = get_chage_exp(username)?.exp_date;
+
+    if current_exp == "never" {
+        return Err(format!(
+            "User {} never expires, use /renew to set an expiry date",
+            username
+        ));
+    }
+
+    let current_date = NaiveDate::parse_from_str(&current_exp, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid expiry date {} of user {}", current_exp, username))?;
+
+    if current_date <= today() {
+        return renew_user(username, days);
+    }
+
+    let exp_date = (current_date + Duration::days(days))
+        .format("%Y-%m-%d")
+        .to_string();
+
+    change_exp(username, &exp_date)
+}
+
+/// Reads the account expiry of a user from `/etc/shadow`, in days since 1970-01-01.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// The raw expiry field, or `None` if the shadow file can't be read, the user isn't in it or the
+/// account never expires.
+pub fn get_expiry_epoch_days(username: &str) -> Option<i64> {
+    read_shadow_entry(username).ok()?.expire_days
+}
+
+/// Represents the fields of a user's `/etc/shadow` line that the bot uses.
+pub struct ShadowEntry {
+    pub username: String,
+    pub password_hash: String,
+    /// Account expiry in days since 1970-01-01, `None` if the account never expires.
+    pub expire_days: Option<i64>,
+}
+
+impl ShadowEntry {
+    /// Returns whether the password is locked.
+    pub fn is_locked(&self) -> bool {
+        self.password_hash.starts_with('!')
+    }
+}
+
+/// Reads and parses the `/etc/shadow` line of a user.
+///
+/// Every feature reading `/etc/shadow` goes through this, so a bot without permission to read it
+/// gets the same clear error everywhere.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the `ShadowEntry`, or an error message if `/etc/shadow` can't be read, the
+/// user isn't in it or its line is malformed.
+pub fn read_shadow_entry(username: &str) -> Result<ShadowEntry, String> {
+    let shadow = std::fs::read_to_string("/etc/shadow").map_err(|err| {
+        format!(
+            "Couldn't read /etc/shadow, the bot needs to run as root: {}",
+            err
+        )
+    })?;
+
+    parse_shadow_entry(&shadow, username)
+}
+
+/// Finds and parses the line of a user in the contents of `/etc/shadow`.
+fn parse_shadow_entry(shadow: &str, username: &str) -> Result<ShadowEntry, String> {
+    let fields: Vec<&str> = shadow
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields[0] == username)
+        .ok_or_else(|| format!("User {} isn't in /etc/shadow", username))?;
+
+    let malformed = || format!("Malformed /etc/shadow line of {}", username);
+
+    let expire_days = match *fields.get(7).ok_or_else(malformed)? {
+        "" => None,
+        days => Some(days.parse::<i64>().map_err(|_| malformed())?),
+    };
+
+    Ok(ShadowEntry {
+        username: username.to_string(),
+        password_hash: fields.get(1).ok_or_else(malformed)?.to_string(),
+        expire_days,
+    })
+}
+
+/// Returns whether a user's password is locked, or `None` if `/etc/shadow` can't be read.
+pub fn is_user_locked(username: &str) -> Option<bool> {
+    read_shadow_entry(username)
+        .map(|shadow_entry| shadow_entry.is_locked())
+        .ok()
+}
+
+fn epoch_days_to_date(days: i64) -> String {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let date = epoch + Duration::days(days);
+    date.format("%Y-%m-%d").to_string()
+}
+
+fn expiry_comment(exp_date: &str) -> String {
+    format!("expiry={}", exp_date)
+}
+
+/// Reads the fields of the `/etc/passwd` line of a user.
+fn read_passwd_entry(username: &str) -> Option<Vec<String>> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+
+    passwd
+        .lines()
+        .map(|line| {
+            line.split(':')
+                .map(|field| field.to_string())
+                .collect::<Vec<String>>()
+        })
+        .find(|fields| fields[0] == username)
+}
+
+/// Finds the value of a `key=value` part of a comma-separated comment.
+fn find_comment_field<'a>(comment: &'a str, key: &str) -> Option<&'a str> {
+    comment
+        .split(',')
+        .find_map(|part| part.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// Gets a `key=value` field from the comment (GECOS) field of a user.
+pub fn get_comment_field(username: &str, key: &str) -> Option<String> {
+    let fields = read_passwd_entry(username)?;
+    let comment = fields.get(4)?;
+
+    find_comment_field(comment, key).map(|value| value.to_string())
+}
+
+/// Sets a `key=value` field in the comment (GECOS) field of a user, keeping the other fields.
+pub fn set_comment_field(username: &str, key: &str, value: &str) -> Result<(), String> {
+    write_comment_field(username, key, Some(value))
+}
+
+/// Removes a `key=value` field from the comment (GECOS) field of a user.
+pub fn remove_comment_field(username: &str, key: &str) -> Result<(), String> {
+    write_comment_field(username, key, None)
+}
+
+fn write_comment_field(username: &str, key: &str, value: Option<&str>) -> Result<(), String> {
+    let fields = read_passwd_entry(username).ok_or("Invalid user or group".to_string())?;
+
+    let mut comment_parts: Vec<String> = fields
+        .get(4)
+        .map(|comment| {
+            comment
+                .split(',')
+                .filter(|part| !part.is_empty() && part.split('=').next() != Some(key))
+                .map(|part| part.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if let Some(value) = value {
+        comment_parts.push(format!("{}={}", key, value));
+    }
+
+    let process_status = sbin_command("usermod")
+        .arg(username)
+        .arg("-c")
+        .arg(comment_parts.join(","))
+        .status();
+
+    match process_status {
+        Ok(status) => {
+            if let Some(error) = unixuser_code_to_err(status.code()) {
+                Err(error)
+            } else {
+                Ok(())
+            }
+        }
+        Err(_) => Err("Command usermod not found".to_string()),
+    }
+}
+
+/// Records the expiry date in the comment (GECOS) field of a user, as a fallback for `chage`.
+fn set_comment_expiry(username: &str, exp_date: &str) {
+    if let Err(err) = set_comment_field(username, "expiry", exp_date) {
+        log::warn!("Couldn't set expiry comment of {}: {}", username, err);
+    }
+}
+
+/// Gets the expiry date recorded in the comment (GECOS) field of a user.
+pub fn get_comment_expiry(username: &str) -> Option<String> {
+    get_comment_field(username, "expiry")
+}
+
+/// Gets the chat id of the admin who created a user, recorded in its comment field.
+pub fn get_user_owner(username: &str) -> Option<i64> {
+    get_comment_field(username, "owner")?.parse().ok()
+}
+
+/// Records the chat id of the admin who owns a user in its comment field.
+pub fn set_user_owner(username: &str, owner: i64) -> Result<(), String> {
+    set_comment_field(username, "owner", &owner.to_string())
+}
+
+/// Counts the users owned by an admin.
+pub fn count_owned_users(owner: i64) -> usize {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let owner = owner.to_string();
+
+    passwd
+        .lines()
+        .filter_map(|line| line.split(':').nth(4))
+        .filter(|comment| find_comment_field(comment, "owner") == Some(owner.as_str()))
+        .count()
+}
+
+/// Returns whether a user ID is listed in `super_admins`.
+pub fn is_super_admin(id: i64) -> bool {
+    config::CONFIG.super_admins.contains(&id)
+}
+
+/// Transfers the ownership of a user to another admin.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `requester` - The ID of the admin requesting the transfer, who must own the user or be a
+///   super-admin.
+/// * `new_owner` - The ID of the admin receiving the user, who must be an admin.
+///
+/// # Returns
+///
+/// A `Result` containing the `UserOwner` if successful, or an error message if the transfer fails.
+pub fn transfer_user(username: &str, requester: i64, new_owner: i64) -> Result<UserOwner, String> {
+    if !config::CONFIG.is_admin(new_owner) {
+        return Err(format!("{} is not an admin", new_owner));
+    }
+
+    if get_user_owner(username) != Some(requester) && !is_super_admin(requester) {
+        return Err("Only the owner or a super-admin can transfer this user".to_string());
+    }
+
+    set_user_owner(username, new_owner)?;
+
+    Ok(UserOwner {
+        username: username.to_string(),
+        owner: new_owner,
+    })
+}
+
+/// Checks that an admin hasn't reached their quota in `admin_quotas`, if they have one.
+pub fn check_quota(owner: i64) -> Result<(), String> {
+    if let Some(quota) = config::CONFIG.admin_quotas.get(&owner) {
+        if count_owned_users(owner) >= *quota as usize {
+            return Err("Quota reached".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts the users managed by the bot, see `is_managed_user`.
+pub fn count_managed_users() -> usize {
+    all_usernames()
+        .iter()
+        .filter(|username| is_managed_user(username))
+        .count()
+}
+
+/// Checks that the number of managed users is below `max_total_users`, if configured.
+pub fn check_server_capacity() -> Result<(), String> {
+    if let Some(max_total_users) = config::CONFIG.max_total_users {
+        if count_managed_users() >= max_total_users {
+            return Err(format!("Server full, {} users at most", max_total_users));
+        }
+    }
+
+    Ok(())
+}
+
+/// Represents a mismatch between the system expiry date and the one in the comment field.
+#[derive(Serialize)]
+pub struct ExpiryDrift {
+    pub username: String,
+    pub system_exp_date: String,
+    pub comment_exp_date: String,
+}
+
+impl fmt::Display for ExpiryDrift {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "username: `{}`\nsystem expiry date: `{}`\ncomment expiry date: `{}`",
+            self.username,
+            display_date(&self.system_exp_date),
+            display_date(&self.comment_exp_date)
+        )
+    }
+}
+
+/// Represents the expiry date of a user as read from `/etc/shadow` and from `chage -l`.
+#[derive(Serialize)]
+pub struct ExpiryCheck {
+    pub username: String,
+    pub shadow_exp_date: String,
+    pub chage_exp_date: String,
+    pub fixed: bool,
+}
+
+impl fmt::Display for ExpiryCheck {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let result = if self.shadow_exp_date == self.chage_exp_date {
+            "consistent"
+        } else if self.fixed {
+            "mismatch, fixed"
+        } else {
+            "mismatch"
+        };
+
+        write!(
+            formatter,
+            "username: `{}`\nshadow expiry date: `{}`\nchage expiry date: `{}`\nresult: `{}`",
+            self.username,
+            display_date(&self.shadow_exp_date),
+            display_date(&self.chage_exp_date),
+            result
+        )
+    }
+}
+
+/// Compares the expiry date of a user in `/etc/shadow` with the one `chage -l` reports, which can
+/// disagree when `EXPIRE` defaults in `login.defs` interfere.
+///
+/// With `fix`, a mismatch is normalized by setting the expiry date again with `chage -E`, using
+/// the date in the comment field plus `grace_period_days` when there is one, or else the one in
+/// `/etc/shadow`.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `fix` - Whether to normalize a mismatch.
+///
+/// # Returns
+///
+/// A `Result` containing the `ExpiryCheck`, or an error message if either date can't be read or
+/// the fix fails.
+pub fn verify_expiry(username: &str, fix: bool) -> Result<ExpiryCheck, String> {
+    let shadow_exp_date = read_shadow_entry(username)?
+        .expire_days
+        .map_or("never".to_string(), epoch_days_to_date);
+    let chage_exp_date = get_chage_list_exp(username)?.exp_date;
+
+    let mismatch = shadow_exp_date != chage_exp_date;
+
+    if mismatch && fix {
+        let exp_date = get_comment_expiry(username)
+            .map(|comment_exp_date| with_grace_period(&comment_exp_date))
+            .unwrap_or_else(|| shadow_exp_date.clone());
+
+        // chage -E takes -1 to remove the expiry date.
+        let chage_arg = if exp_date == "never" {
+            "-1".to_string()
+        } else {
+            exp_date
+        };
+
+        let process_status = sbin_command("chage")
+            .arg(username)
+            .arg("-E")
+            .arg(chage_arg)
+            .status();
+
+        match process_status {
+            Ok(status) => {
+                if let Some(error) = unixuser_code_to_err(status.code()) {
+                    return Err(error);
+                }
+            }
+            Err(_) => return Err("Command chage not found".to_string()),
+        }
+    }
+
+    Ok(ExpiryCheck {
+        username: username.to_string(),
+        shadow_exp_date,
+        chage_exp_date,
+        fixed: mismatch && fix,
+    })
+}
+
+/// Compares the system expiry date of a user with the one recorded in the comment field, plus
+/// `grace_period_days`.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the `ExpiryDrift` if both dates exist and disagree, or `None` if they
+/// agree or no comment date was recorded.
+pub fn detect_expiry_drift(username: &str) -> Result<Option<ExpiryDrift>, String> {
+    let user_exp = get_system_exp(username)?;
+
+    match get_comment_expiry(username) {
+        Some(comment_exp_date) if with_grace_period(&comment_exp_date) != user_exp.exp_date => {
+            Ok(Some(ExpiryDrift {
+                username: username.to_string(),
+                system_exp_date: user_exp.exp_date,
+                comment_exp_date,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Represents the differences between the system users with a prefix and the users the bot tracks
+/// through an `expiry=` field in their comment.
+pub struct UserDiff {
+    /// Users with the prefix that the bot has no record of, e.g. added with `useradd` by hand.
+    pub untracked: Vec<String>,
+    /// Users the bot has a record of that have none of the bot's prefixes, e.g. renamed by hand.
+    pub outside_prefix: Vec<String>,
+}
+
+impl fmt::Display for UserDiff {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let list = |usernames: &[String]| {
+            if usernames.is_empty() {
+                "none".to_string()
+            } else {
+                usernames.join(", ")
+            }
+        };
+
+        write!(
+            formatter,
+            "On the system but not tracked: {}\nTracked but without a prefix: {}",
+            list(&self.untracked),
+            list(&self.outside_prefix)
+        )
+    }
+}
+
+impl UserDiff {
+    /// Returns whether the system and the tracked users agree.
+    pub fn is_empty(&self) -> bool {
+        self.untracked.is_empty() && self.outside_prefix.is_empty()
+    }
+}
+
+/// Compares the system users with a prefix against the users the bot tracks in their comment
+/// field, reading `/etc/passwd` once.
+///
+/// # Arguments
+///
+/// * `prefix` - The username prefix of the managed users.
+///
+/// # Returns
+///
+/// A `Result` containing the `UserDiff`, or an error message if `/etc/passwd` can't be read.
+pub fn diff_users(prefix: &str) -> Result<UserDiff, String> {
+    let passwd = std::fs::read_to_string("/etc/passwd")
+        .map_err(|e| format!("Couldn't read /etc/passwd: {}", e))?;
+
+    let config_file: &config::ConfigFile = &config::CONFIG;
+    let mut prefixes: Vec<String> = config_file.admin_prefixes.values().cloned().collect();
+    prefixes.push(config_file.active_prefix());
+
+    Ok(diff_passwd(&passwd, prefix, &prefixes))
+}
+
+/// Compares the users with a prefix in the contents of `/etc/passwd` against the ones with an
+/// `expiry=` comment field. Tracked users are only reported when they have none of `prefixes`.
+fn diff_passwd(passwd: &str, prefix: &str, prefixes: &[String]) -> UserDiff {
+    let mut diff = UserDiff {
+        untracked: Vec::new(),
+        outside_prefix: Vec::new(),
+    };
+
+    for line in passwd.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        let username = fields[0];
+        let tracked = fields.get(4).map_or(false, |comment| {
+            find_comment_field(comment, "expiry").is_some()
+        });
+
+        if username.starts_with(prefix) && !tracked {
+            diff.untracked.push(username.to_string());
+        } else if tracked && !prefixes.iter().any(|prefix| username.starts_with(prefix)) {
+            diff.outside_prefix.push(username.to_string());
+        }
+    }
+
+    diff
+}
+
+/// Re-applies the state recorded in the comment field of a user where the system has drifted from
+/// it: the expiry date, and the group and lock of quarantined users.
+///
+/// Re-applying is idempotent, so users that haven't drifted are left untouched.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A description of every correction made or attempted.
+pub fn reconcile_user(username: &str) -> Vec<String> {
+    let mut corrections: Vec<String> = Vec::new();
+
+    match detect_expiry_drift(username) {
+        Ok(Some(expiry_drift)) => {
+            corrections.push(match change_exp(username, &expiry_drift.comment_exp_date) {
+                Ok(user_exp) => format!(
+                    "Reset expiry of {} from {} to {}",
+                    username, expiry_drift.system_exp_date, user_exp.exp_date
+                ),
+                Err(err) => format!("Couldn't reset expiry of {}: {}", username, err),
+            });
+        }
+        Ok(None) => {}
+        Err(err) => log::warn!("Couldn't check expiry of {}: {}", username, err),
+    }
+
+    if get_comment_field(username, "quarantined_from").is_none() {
+        return corrections;
+    }
+
+    if let Some(quarantine_group) = &config::CONFIG.quarantine_group {
+        if get_primary_group(username).as_ref() != Some(quarantine_group) {
+            corrections.push(match set_primary_group(username, quarantine_group) {
+                Ok(_) => format!(
+                    "Moved quarantined user {} back to {}",
+                    username, quarantine_group
+                ),
+                Err(err) => format!("Couldn't move quarantined user {}: {}", username, err),
+            });
+        }
+    }
+
+    if is_user_locked(username) == Some(false) {
+        corrections.push(match lock_user(username) {
+            Ok(_) => format!("Locked quarantined user {}", username),
+            Err(err) => format!("Couldn't lock quarantined user {}: {}", username, err),
+        });
+    }
+
+    corrections
+}
+
+/// Represents the raw account data of a user, for debugging expiry parsing.
+#[derive(Serialize)]
+pub struct UserDump {
+    pub username: String,
+    pub chage: String,
+    pub passwd: String,
+}
+
+impl fmt::Display for UserDump {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "username: `{}`\nchage -l:\n```\n{}\n```\n/etc/passwd:\n```\n{}\n```",
+            self.username,
+            self.chage.trim_end(),
+            self.passwd
+        )
+    }
+}
+
+/// Gets the raw `chage -l` output and `/etc/passwd` line of a user.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the `UserDump`, with the password field of the passwd line replaced by
+/// `x`, or an error message if the user doesn't exist.
+pub fn dump_user(username: &str) -> Result<UserDump, String> {
+    let mut passwd_fields = read_passwd_entry(username).ok_or("User not found")?;
+    if let Some(password) = passwd_fields.get_mut(1) {
+        *password = "x".to_string();
+    }
+
+    let process_output = sbin_command("chage").arg("-l").arg(username).output();
+    match process_output {
+        Ok(output) => Ok(UserDump {
+            username: username.to_string(),
+            chage: format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            passwd: passwd_fields.join(":"),
+        }),
+        Err(_) => Err("Command chage not found".to_string()),
+    }
+}
+
+/// Gets the expiry date of a user.
+///
+/// The system value is preferred, falling back to the date recorded in the comment field when it
+/// can't be read. A warning is logged when both exist and disagree. With `grace_period_days` set,
+/// the comment date is the real expiry and is preferred instead, since the system one includes the
+/// grace period.
+pub fn get_chage_exp(username: &str) -> Result<UserExp, String> {
+    match get_system_exp(username) {
+        Ok(user_exp) => {
+            if let Some(comment_exp_date) = get_comment_expiry(username) {
+                if with_grace_period(&comment_exp_date) != user_exp.exp_date {
+                    log::warn!(
+                        "Expiry of {} is {} but its comment says {}",
+                        username,
+                        user_exp.exp_date,
+                        comment_exp_date
+                    );
+                }
+
+                if config::CONFIG.grace_period_days > 0 {
+                    return Ok(UserExp {
+                        username: username.to_string(),
+                        exp_date: comment_exp_date,
+                    });
+                }
+            }
+
+            Ok(user_exp)
+        }
+        Err(err) => match get_comment_expiry(username) {
+            Some(exp_date) => Ok(UserExp {
+                username: username.to_string(),
+                exp_date,
+            }),
+            None => Err(err),
+        },
+    }
+}
+
+/// Gets the system expiry date of a user, reading `/etc/shadow` and falling back to parsing
+/// `chage -l`.
+fn get_system_exp(username: &str) -> Result<UserExp, String> {
+    if let Some(days) = get_expiry_epoch_days(username) {
+        return Ok(UserExp {
+            username: username.to_string(),
+            exp_date: epoch_days_to_date(days),
+        });
+    }
+
+    get_chage_list_exp(username)
+}
+
+/// Gets the expiry date of a user by parsing `chage -l`.
+fn get_chage_list_exp(username: &str) -> Result<UserExp, String> {
+    let process_output = sbin_command("chage")
+        .env("LC_ALL", "C")
+        .arg("-l")
+        .arg(username)
+        .output();
+    match process_output {
+        Ok(output) => {
+            if let Some(error) = unixuser_code_to_err(output.status.code()) {
+                Err(error)
+            } else {
+                let user_info = String::from_utf8(output.stdout).unwrap();
+
+                let re = Regex::new("Account expires\t+: (.*)\n").unwrap();
+
+                let caps = re.captures(&user_info);
+
+                if let Some(caps) = caps {
+                    let exp_date = caps.get(1).map_or("", |m| m.as_str()).to_string();
+                    if &exp_date == "never" {
+                        return Ok(UserExp {
+                            username: username.to_string(),
+                            exp_date: "never".to_string(),
+                        });
+                    }
+
+                    let inp_format = format_description!("[month repr:short] [day], [year]");
+
+                    let out_format = format_description!("[year]-[month]-[day]");
+                    match Date::parse(&exp_date, &inp_format) {
+                        Ok(date) => Ok(UserExp {
+                            username: username.to_string(),
+                            exp_date: date.format(&out_format).unwrap(),
+                        }),
+                        Err(_) => Err("Invalid Expiry date".to_string()),
+                    }
+                } else {
+                    Err("Unexpected error".to_string())
+                }
+            }
+        }
+        Err(_e) => Err("Command chage not found".to_string()),
+    }
+}
+
+/// Gets the trimmed stderr of a command, or its stdout when stderr is empty.
+fn command_error_output(output: &std::process::Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+
+    if stderr.is_empty() {
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    } else {
+        stderr
+    }
+}
+
+/// Reloads the SSH server with `ssh_reload_command`, after checking its configuration with
+/// `sshd -t` so a broken config can't lock everyone out.
+///
+/// # Returns
+///
+/// A `Result` containing a report if successful, or an error message with the output of `sshd -t`
+/// or of the reload command if either fails.
+pub fn reload_ssh() -> Result<String, String> {
+    let check = sbin_command("sshd")
+        .arg("-t")
+        .output()
+        .map_err(|_| "Command sshd not found".to_string())?;
+
+    if !check.status.success() {
+        return Err(format!(
+            "sshd config is invalid, not reloading:\n{}",
+            command_error_output(&check)
+        ));
+    }
+
+    let (program, args) = config::CONFIG
+        .ssh_reload_command
+        .split_first()
+        .ok_or("ssh_reload_command is empty".to_string())?;
+
+    let reload = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|_| format!("Command {} not found", program))?;
+
+    if reload.status.success() {
+        Ok(format!(
+            "Reloaded SSH with `{}`",
+            config::CONFIG.ssh_reload_command.join(" ")
+        ))
+    } else {
+        Err(format!(
+            "Couldn't reload SSH:\n{}",
+            command_error_output(&reload)
+        ))
+    }
+}
+
+/// Builds a `Command` for a user management binary, prefixed with `sbin_path` when configured.
+fn sbin_command(name: &str) -> Command {
+    match &config::CONFIG.sbin_path {
+        Some(sbin_path) => Command::new(Path::new(sbin_path).join(name)),
+        None => Command::new(name),
+    }
+}
+
+/// Maps the exit code of `useradd`/`usermod`/`chage` to an error message.
+///
+/// See the EXIT VALUES section of useradd(8) for the full list of codes. Messages in
+/// `error_code_map` take precedence over the builtin ones.
+fn unixuser_code_to_err(code: Option<i32>) -> Option<String> {
+    code_to_err(code, &config::CONFIG.error_code_map)
+}
+
+/// Maps an exit code to an error message, preferring the messages in `error_code_map`.
+fn code_to_err(code: Option<i32>, error_code_map: &HashMap<i32, String>) -> Option<String> {
+    if let Some(code) = code {
+        if code != 0 {
+            if let Some(message) = error_code_map.get(&code) {
+                return Some(message.clone());
+            }
+        }
+
+        match code {
+            0 => None,
             1 => Some("Permission denied".to_string()),
+            2 => Some("Invalid command syntax".to_string()),
             3 => Some("Invalid shell".to_string()),
+            4 => Some("UID already in use".to_string()),
             6 => Some("Invalid user or group".to_string()),
-            9 => Some("User alreade exists".to_string()),
+            9 => Some("User already exists".to_string()),
+            10 => Some("Can't update group file".to_string()),
+            12 => Some("Can't create home directory, check disk space and /etc/skel".to_string()),
+            13 => Some("Can't create mail spool".to_string()),
+            14 => Some("Can't update SELinux user mapping".to_string()),
             _ => Some("Unexpected error".to_string()),
         }
     } else {
-        Some("Process terminated".to_string())
+        Some("Process terminated".to_string())
+    }
+}
+
+/// Checks whether a system user exists.
+pub fn user_exists(username: &str) -> bool {
+    users::get_user_by_name(username).is_some()
+}
+
+/// Validates a number of days given to a day-taking command.
+///
+/// # Arguments
+///
+/// * `days` - The number of days to validate.
+/// * `max_days` - The maximum number of days allowed, if any.
+///
+/// # Returns
+///
+/// An error message if `days` is less than 1 or more than `max_days`.
+pub fn validate_days(days: i64, max_days: Option<i64>) -> Result<(), String> {
+    if days < 1 {
+        return Err("Days must be at least 1".to_string());
+    }
+
+    if let Some(max_days) = max_days {
+        if days > max_days {
+            return Err(format!("Days must be at most {}", max_days));
+        }
+    }
+
+    Ok(())
+}
+
+/// Formats a `YYYY-MM-DD` date with the configured `date_format` for display in bot replies.
+///
+/// Values that aren't dates, like `never`, are returned unchanged.
+pub fn display_date(date: &str) -> String {
+    match (
+        &config::CONFIG.date_format,
+        NaiveDate::parse_from_str(date, "%Y-%m-%d"),
+    ) {
+        (Some(date_format), Ok(date)) => date.format(date_format).to_string(),
+        _ => date.to_string(),
+    }
+}
+
+/// Today's date in the configured `timezone`, or in the system's local timezone when unset.
+pub fn today() -> NaiveDate {
+    local_date(Utc::now())
+}
+
+/// The date of a point in time in the configured `timezone`, or in the system's local timezone
+/// when unset.
+fn local_date(time: DateTime<Utc>) -> NaiveDate {
+    match config::CONFIG
+        .timezone
+        .as_deref()
+        .and_then(|timezone| timezone.parse::<Tz>().ok())
+    {
+        Some(timezone) => time.with_timezone(&timezone).date_naive(),
+        None => time.with_timezone(&Local).date_naive(),
+    }
+}
+
+/// Adds `grace_period_days` to a `YYYY-MM-DD` expiry date, giving the date the account is actually
+/// disabled on. Other values, like `never`, are returned unchanged.
+fn with_grace_period(exp_date: &str) -> String {
+    let grace_period_days = config::CONFIG.grace_period_days;
+
+    match NaiveDate::parse_from_str(exp_date, "%Y-%m-%d") {
+        Ok(date) if grace_period_days > 0 => (date + Duration::days(grace_period_days.into()))
+            .format("%Y-%m-%d")
+            .to_string(),
+        _ => exp_date.to_string(),
+    }
+}
+
+/// Returns whether a user has expired but is still within `grace_period_days` of its expiry date.
+pub fn in_grace_period(username: &str) -> bool {
+    let grace_period_days = config::CONFIG.grace_period_days;
+    let today = today();
+
+    grace_period_days > 0
+        && get_expiry_date(username).map_or(false, |date| {
+            date <= today && today < date + Duration::days(grace_period_days.into())
+        })
+}
+
+fn add_to_time(days: i64) -> String {
+    let now = today();
+    let future_date = now + Duration::days(days);
+    let formatted_date = future_date.format("%Y-%m-%d").to_string();
+    format!("{}", formatted_date)
+}
+
+/// Lists the names of all system users.
+///
+/// The `users` crate enumeration can come back empty or partial in minimal containers without a
+/// full passwd database, so names only found in `/etc/passwd` are added to it, with a warning
+/// logged the first time that happens.
+pub fn all_usernames() -> Vec<String> {
+    static WARNED: Once = Once::new();
+
+    let mut usernames: Vec<String> = unsafe { users::all_users() }
+        .map(|user| user.name().to_string_lossy().to_string())
+        .collect();
+
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let enumerated = usernames.len();
+    let mut seen: HashSet<String> = usernames.iter().cloned().collect();
+
+    for line in passwd.lines() {
+        if let Some(username) = line.split(':').next().filter(|name| !name.is_empty()) {
+            if seen.insert(username.to_string()) {
+                usernames.push(username.to_string());
+            }
+        }
+    }
+
+    if usernames.len() > enumerated {
+        WARNED.call_once(|| {
+            log::warn!(
+                "User enumeration returned {} users but /etc/passwd has more, reading it directly",
+                enumerated
+            );
+        });
+    }
+
+    usernames
+}
+
+pub fn get_users_core(prefix: &str, usergroup: Option<&str>) -> Vec<String> {
+    let mut users_list: Vec<String> = Vec::new();
+
+    for username in all_usernames() {
+        if username.starts_with(prefix) {
+            if let Some(usergroup) = usergroup {
+                let groups = users::get_user_by_name(&username).and_then(|user| user.groups());
+                if let Some(groups) = groups {
+                    let group = groups.iter().find(|g| g.name() == usergroup);
+                    if group.is_some() {
+                        users_list.push(username);
+                    }
+                }
+            } else {
+                users_list.push(username);
+            }
+        }
+    }
+
+    users_list
+}
+
+/// Counts the users whose name starts with `prefix`, with a single read of `/etc/passwd` and no
+/// group lookups.
+pub fn count_users_with_prefix(prefix: &str) -> usize {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+
+    count_passwd_prefix(&passwd, prefix)
+}
+
+/// Returns the highest numeric suffix among users named `<prefix><number>`, or 0 if there are none.
+///
+/// Unlike counting users, this doesn't hand out an existing name again after a user in the middle
+/// of the sequence is deleted. It only reads `/etc/passwd` once and doesn't look up groups.
+pub fn max_user_suffix(prefix: &str) -> usize {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+
+    max_passwd_suffix(&passwd, prefix)
+}
+
+/// Lists the usernames in the contents of `/etc/passwd`.
+fn passwd_usernames(passwd: &str) -> impl Iterator<Item = &str> {
+    passwd
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .filter(|username| !username.is_empty())
+}
+
+fn count_passwd_prefix(passwd: &str, prefix: &str) -> usize {
+    passwd_usernames(passwd)
+        .filter(|username| username.starts_with(prefix))
+        .count()
+}
+
+fn max_passwd_suffix(passwd: &str, prefix: &str) -> usize {
+    passwd_usernames(passwd)
+        .filter_map(|username| {
+            let suffix = username.strip_prefix(prefix)?;
+
+            if !is_sequential_suffix(suffix) {
+                return None;
+            }
+
+            suffix.parse::<usize>().ok()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Checks that a suffix has the shape `Sequential` usernames get: digits only, and not a
+/// `DatePrefixed` suffix such as `24013102`, so one date-prefixed user doesn't make the sequence
+/// jump to its number.
+fn is_sequential_suffix(suffix: &str) -> bool {
+    if suffix.is_empty() || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+
+    suffix.len() < 8 || NaiveDate::parse_from_str(&suffix[..6], "%y%m%d").is_err()
+}
+
+/// Checks that a group is listed in `allowed_groups`, if the config restricts groups.
+pub fn check_allowed_group(group: &str) -> Result<(), String> {
+    check_argument(group)?;
+
+    match &config::CONFIG.allowed_groups {
+        Some(allowed_groups) if !allowed_groups.iter().any(|allowed| allowed == group) => {
+            Err(format!("Group {} is not allowed", group))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Rejects a value passed to `useradd`/`usermod`/`chage` that starts with `-`, so it can't be read
+/// as an option.
+pub fn check_argument(value: &str) -> Result<(), String> {
+    if value.starts_with('-') {
+        return Err(format!("Invalid argument {}, it can't start with -", value));
+    }
+
+    Ok(())
+}
+
+/// Parses the number of allowed logins from a `maxN` group name, e.g. `2` from `max2`.
+pub fn parse_max_logins(group: &str) -> Option<u32> {
+    max_logins_digits(group)?.parse().ok()
+}
+
+/// Returns the number of a group named `max` followed by ASCII digits only, so names like
+/// `max+2` aren't taken for a `maxN` group.
+fn max_logins_digits(group: &str) -> Option<&str> {
+    let digits = group.strip_prefix("max")?;
+
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(digits)
+}
+
+/// Describes the max logins of a group, `unknown` for groups not named `maxN`.
+pub fn max_logins_label(group: &str) -> String {
+    parse_max_logins(group).map_or("unknown".to_string(), |logins| logins.to_string())
+}
+
+/// Checks that the logins of a `maxN` group are within `min_logins` and `max_logins`, if
+/// configured. Groups not named `maxN` aren't checked.
+pub fn check_max_logins(group: &str) -> Result<(), String> {
+    let config_file: &config::ConfigFile = &config::CONFIG;
+
+    check_logins_range(group, config_file.min_logins, config_file.max_logins)
+}
+
+/// Checks that the logins of a `maxN` group are within `min_logins` and `max_logins`. Groups whose
+/// number doesn't fit in a `u32` are always out of range.
+fn check_logins_range(
+    group: &str,
+    min_logins: Option<u32>,
+    max_logins: Option<u32>,
+) -> Result<(), String> {
+    let Some(digits) = max_logins_digits(group) else {
+        return Ok(());
+    };
+
+    let Ok(logins) = digits.parse::<u32>() else {
+        return Err(match max_logins {
+            Some(max_logins) => format!("Max logins must be at most {}", max_logins),
+            None => format!("Max logins of group {} is out of range", group),
+        });
+    };
+
+    if let Some(min_logins) = min_logins {
+        if logins < min_logins {
+            return Err(format!("Max logins must be at least {}", min_logins));
+        }
+    }
+
+    if let Some(max_logins) = max_logins {
+        if logins > max_logins {
+            return Err(format!("Max logins must be at most {}", max_logins));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes `/etc/security/limits.d/<group>.conf` limiting the members of a group to `n` concurrent
+/// logins, with `pam_limits` enforcing it. The file is left untouched if it's already up to date.
+///
+/// # Arguments
+///
+/// * `group` - The name of the group.
+/// * `n` - The maximum number of concurrent logins.
+///
+/// # Returns
+///
+/// An error message if the file can't be written.
+pub fn apply_maxlogins_limit(group: &str, n: u32) -> Result<(), String> {
+    let path = Path::new("/etc/security/limits.d").join(format!("{}.conf", group));
+    let content = format!("@{} - maxlogins {}\n", group, n);
+
+    if std::fs::read_to_string(&path).ok().as_deref() == Some(content.as_str()) {
+        return Ok(());
+    }
+
+    std::fs::write(&path, content).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))
+}
+
+/// Applies the maxlogins limit of a `maxN` group when `manage_limits` is set, logging failures
+/// rather than failing the caller.
+fn ensure_maxlogins_limit(group: &str) {
+    if !config::CONFIG.manage_limits {
+        return;
+    }
+
+    if let Some(n) = parse_max_logins(group) {
+        if let Err(err) = apply_maxlogins_limit(group, n) {
+            log::warn!("Couldn't apply maxlogins limit of {}: {}", group, err);
+        }
+    }
+}
+
+/// Filters users by group membership.
+pub enum GroupFilter {
+    /// Any group.
+    Any,
+    /// Members of the given group.
+    Group(String),
+}
+
+/// Filters users by expiry status.
+pub enum ExpiryFilter {
+    /// Any expiry status.
+    Any,
+    /// Users that never expire or expire after today.
+    Active,
+    /// Users whose expiry date is today or earlier.
+    Expired,
+    /// Active users expiring within the given number of days.
+    ExpiringWithin(i64),
+}
+
+/// Gets the expiry date of a user, or `None` if the account never expires or can't be read.
+pub fn get_expiry_date(username: &str) -> Option<NaiveDate> {
+    let user_exp = get_chage_exp(username).ok()?;
+    NaiveDate::parse_from_str(&user_exp.exp_date, "%Y-%m-%d").ok()
+}
+
+/// Gets the expiry dates of every user with the given prefix, reading `/etc/shadow` and
+/// `/etc/passwd` once instead of once per user.
+///
+/// Dates are picked like `get_chage_exp` does, but without falling back to `chage -l`, so users
+/// whose expiry isn't in either file are left out, as are users that never expire.
+///
+/// # Returns
+///
+/// The username and expiry date of every user that has one, in `/etc/passwd` order.
+pub fn get_all_expiries(prefix: &str) -> Vec<(String, NaiveDate)> {
+    let shadow = std::fs::read_to_string("/etc/shadow").unwrap_or_default();
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let prefer_comment = config::CONFIG.grace_period_days > 0;
+
+    let shadow_dates: HashMap<&str, NaiveDate> = shadow
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let days: i64 = fields.get(7)?.parse().ok()?;
+            Some((fields[0], epoch + Duration::days(days)))
+        })
+        .collect();
+
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let username = fields[0];
+            if !username.starts_with(prefix) {
+                return None;
+            }
+
+            let comment_date = fields
+                .get(4)
+                .and_then(|comment| find_comment_field(comment, "expiry"))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            let shadow_date = shadow_dates.get(username).copied();
+
+            let date = if prefer_comment {
+                comment_date.or(shadow_date)
+            } else {
+                shadow_date.or(comment_date)
+            }?;
+
+            Some((username.to_string(), date))
+        })
+        .collect()
+}
+
+/// Gets the users with the given prefix, filtered by group and expiry status.
+///
+/// # Arguments
+///
+/// * `prefix` - The prefix of the usernames.
+/// * `group_filter` - The group the users must be a member of.
+/// * `expiry_filter` - The expiry status the users must have.
+///
+/// # Returns
+///
+/// The usernames matching every filter.
+pub fn get_users_filtered(
+    prefix: &str,
+    group_filter: GroupFilter,
+    expiry_filter: ExpiryFilter,
+) -> Vec<String> {
+    let usergroup = match &group_filter {
+        GroupFilter::Any => None,
+        GroupFilter::Group(group) => Some(group.as_str()),
+    };
+
+    let today = today();
+
+    get_users_core(prefix, usergroup)
+        .into_iter()
+        .filter(|username| {
+            if let ExpiryFilter::Any = expiry_filter {
+                return true;
+            }
+
+            let expiry_date = get_expiry_date(username);
+            let is_expired = expiry_date.map_or(false, |date| date <= today);
+
+            match expiry_filter {
+                ExpiryFilter::Any => true,
+                ExpiryFilter::Active => !is_expired,
+                ExpiryFilter::Expired => is_expired,
+                ExpiryFilter::ExpiringWithin(days) => {
+                    !is_expired
+                        && expiry_date.map_or(false, |date| date <= today + Duration::days(days))
+                }
+            }
+        })
+        .collect()
+}
+
+/// Gets the name of the primary group of a user.
+pub fn get_primary_group(username: &str) -> Option<String> {
+    let user = users::get_user_by_name(username)?;
+    let group = users::get_group_by_gid(user.primary_group_id())?;
+    Some(group.name().to_string_lossy().to_string())
+}
+
+/// Lazily builds the records of every user with the given prefix.
+fn user_records(prefix: &str) -> impl Iterator<Item = UserRecord> {
+    get_users_core(prefix, None)
+        .into_iter()
+        .map(|username| UserRecord {
+            group: get_primary_group(&username).unwrap_or_default(),
+            expiry_date: get_chage_exp(&username)
+                .map(|user_exp| user_exp.exp_date)
+                .unwrap_or_default(),
+            username,
+        })
+}
+
+/// Gets the users with the given prefix that expired at least `days` days ago.
+///
+/// # Arguments
+///
+/// * `prefix` - The prefix of the usernames.
+/// * `days` - The minimum number of days since expiry.
+///
+/// # Returns
+///
+/// The `UserExp` of every matching user, oldest expiry first.
+pub fn expired_before(prefix: &str, days: i64) -> Vec<UserExp> {
+    let cutoff = today() - Duration::days(days);
+
+    let mut expired_users: Vec<(NaiveDate, String)> = get_users_core(prefix, None)
+        .into_iter()
+        .filter_map(|username| {
+            let expiry_date = get_expiry_date(&username)?;
+            (expiry_date <= cutoff).then_some((expiry_date, username))
+        })
+        .collect();
+
+    expired_users.sort();
+
+    expired_users
+        .into_iter()
+        .map(|(expiry_date, username)| UserExp {
+            username,
+            exp_date: expiry_date.format("%Y-%m-%d").to_string(),
+        })
+        .collect()
+}
+
+/// Gets the records of every user with the given prefix.
+pub fn get_user_records(prefix: &str) -> Vec<UserRecord> {
+    user_records(prefix).collect()
+}
+
+/// Exports the records of every user with the given prefix as JSON.
+pub fn export_users_json(prefix: &str) -> Result<String, String> {
+    serde_json::to_string_pretty(&get_user_records(prefix)).map_err(|e| e.to_string())
+}
+
+/// Streams the records of every user with the given prefix as JSON Lines, one object per line.
+///
+/// Records are written as they're built, so the whole export is never held in memory.
+///
+/// # Arguments
+///
+/// * `prefix` - The prefix of the usernames to export.
+/// * `writer` - Where the records are written.
+///
+/// # Returns
+///
+/// A `Result` containing the number of records written, or an error message if writing fails.
+pub fn export_users_jsonl_to_writer(prefix: &str, mut writer: impl Write) -> Result<usize, String> {
+    let mut count = 0;
+
+    for record in user_records(prefix) {
+        serde_json::to_writer(&mut writer, &record).map_err(|e| e.to_string())?;
+        writer.write_all(b"\n").map_err(|e| e.to_string())?;
+        count += 1;
+    }
+
+    writer.flush().map_err(|e| e.to_string())?;
+
+    Ok(count)
+}
+
+/// Finds the directories under the home base whose owner no longer exists.
+///
+/// # Arguments
+///
+/// * `home_base` - The directory containing the home directories, e.g. `/home`.
+///
+/// # Returns
+///
+/// A `Result` containing the orphaned directories, or an error message if the home base can't be
+/// read.
+pub fn find_orphan_homes(home_base: &str) -> Result<Vec<PathBuf>, String> {
+    let entries =
+        std::fs::read_dir(home_base).map_err(|e| format!("Couldn't read {}: {}", home_base, e))?;
+
+    let mut orphans: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| {
+            entry.metadata().map_or(false, |metadata| {
+                metadata.is_dir() && users::get_user_by_uid(metadata.uid()).is_none()
+            })
+        })
+        .map(|entry| entry.path())
+        .collect();
+
+    orphans.sort();
+
+    Ok(orphans)
+}
+
+/// Deletes the directories under the home base whose owner no longer exists.
+///
+/// # Returns
+///
+/// A `Result` containing the deleted directories, or an error message if one couldn't be deleted.
+pub fn remove_orphan_homes(home_base: &str) -> Result<Vec<PathBuf>, String> {
+    let orphans = find_orphan_homes(home_base)?;
+
+    for orphan in &orphans {
+        std::fs::remove_dir_all(orphan)
+            .map_err(|e| format!("Couldn't delete {}: {}", orphan.display(), e))?;
+    }
+
+    Ok(orphans)
+}
+
+/// Gets the source IPs of the active SSH connections of every user.
+///
+/// The `sshd` processes of each user are matched against the established TCP connections listed
+/// by `ss`, which needs root to see other users' processes.
+///
+/// # Returns
+///
+/// A `Result` containing the distinct IPs of each connected user, or an error message if the
+/// connections can't be listed.
+pub fn get_all_connection_ips() -> Result<HashMap<String, Vec<String>>, String> {
+    let ps_output = Command::new("ps")
+        .arg("-eo")
+        .arg("user:64,pid,comm")
+        .arg("--no-headers")
+        .output()
+        .map_err(|_| "Command ps not found".to_string())?;
+
+    let sshd_pids: HashMap<String, String> = String::from_utf8_lossy(&ps_output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let user = fields.next()?;
+            let pid = fields.next()?;
+            (fields.next()? == "sshd").then(|| (format!("pid={},", pid), user.to_string()))
+        })
+        .collect();
+
+    let ss_output = Command::new("ss")
+        .arg("-tnpH")
+        .output()
+        .map_err(|_| "Command ss not found".to_string())?;
+
+    if !ss_output.status.success() {
+        return Err("Couldn't list connections".to_string());
+    }
+
+    let mut connection_ips: HashMap<String, Vec<String>> = HashMap::new();
+
+    for line in String::from_utf8_lossy(&ss_output.stdout).lines() {
+        let peer = line
+            .split_whitespace()
+            .nth(4)
+            .and_then(|peer| peer.rsplit_once(':'));
+
+        let Some((ip, _port)) = peer else {
+            continue;
+        };
+        let ip = ip.trim_start_matches('[').trim_end_matches(']').to_string();
+
+        for (pid, user) in &sshd_pids {
+            if line.contains(pid.as_str()) {
+                let ips = connection_ips.entry(user.clone()).or_default();
+                if !ips.contains(&ip) {
+                    ips.push(ip.clone());
+                }
+            }
+        }
+    }
+
+    Ok(connection_ips)
+}
+
+/// Gets the source IPs of the active SSH connections of a user.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the distinct IPs, or an error message if the connections can't be listed.
+pub fn user_connection_ips(username: &str) -> Result<Vec<String>, String> {
+    Ok(get_all_connection_ips()?
+        .remove(username)
+        .unwrap_or_default())
+}
+
+/// Kills every process of a user, ending their SSH sessions.
+pub fn kill_user_sessions(username: &str) -> Result<UserStatus, String> {
+    let process_status = Command::new("pkill").arg("-u").arg(username).status();
+
+    match process_status {
+        // pkill exits with 1 when no process matched, which means there was nothing to kill.
+        Ok(status) if status.code() == Some(0) || status.code() == Some(1) => Ok(UserStatus {
+            username: username.to_string(),
+            status: "Sessions killed".to_string(),
+        }),
+        Ok(_) => Err("Couldn't kill sessions".to_string()),
+        Err(_) => Err("Command pkill not found".to_string()),
+    }
+}
+
+/// Runs a traffic control command, mapping a failure to an error message naming it.
+fn run_tc_command(command: &mut Command, name: &str) -> Result<(), String> {
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err(format!("Command {} failed", name)),
+        Err(_) => Err(format!("Command {} not found", name)),
+    }
+}
+
+/// Caps the throughput of a user on `rate_limit_interface`, or removes the cap when `mbit` is 0.
+///
+/// Packets from the user's processes, which include its SSH sessions and tunnels, are marked with
+/// its UID by an iptables `owner` rule, and a `tc` fw filter sends them to an HTB class with the
+/// given rate. The rate is recorded as `rate_mbit=` in the user's comment field. Neither `tc` nor
+/// iptables rules persist across reboots.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `mbit` - The maximum rate in Mbit/s, or 0 to remove the limit.
+///
+/// # Returns
+///
+/// A `Result` containing the `UserStatus` if successful, or an error message if the interface isn't
+/// configured, the UID doesn't fit in a `tc` class ID, or `tc`/`iptables` fail or are missing.
+pub fn set_rate_limit(username: &str, mbit: u32) -> Result<UserStatus, String> {
+    let interface = config::CONFIG
+        .rate_limit_interface
+        .as_ref()
+        .ok_or("rate_limit_interface is not configured".to_string())?;
+    let uid = users::get_user_by_name(username)
+        .ok_or("Invalid user or group".to_string())?
+        .uid();
+
+    // tc class minor IDs are 16 bits, with 0 and ffff reserved.
+    if uid == 0 || uid >= 0xffff {
+        return Err(format!("UID {} can't be used as a tc class ID", uid));
+    }
+
+    let classid = format!("1:{:x}", uid);
+    let mark = uid.to_string();
+    let iptables_rule = rate_limit_iptables_rule(&mark);
+
+    if mbit == 0 {
+        remove_rate_limit_rules(interface, uid)?;
+        remove_comment_field(username, "rate_mbit")?;
+
+        return Ok(UserStatus {
+            username: username.to_string(),
+            status: "Rate limit removed".to_string(),
+        });
+    }
+
+    let qdisc = Command::new("tc")
+        .args(["qdisc", "show", "dev", interface, "root"])
+        .output()
+        .map_err(|_| "Command tc not found".to_string())?;
+
+    // Unclassified traffic goes to the missing default class, which leaves it unlimited.
+    if !String::from_utf8_lossy(&qdisc.stdout).contains("htb 1:") {
+        run_tc_command(
+            Command::new("tc")
+                .args(["qdisc", "add", "dev", interface, "root", "handle", "1:"])
+                .args(["htb", "default", "ffff"]),
+            "tc qdisc",
+        )?;
+    }
+
+    run_tc_command(
+        Command::new("tc")
+            .args([
+                "class", "replace", "dev", interface, "parent", "1:", "classid", &classid,
+            ])
+            .args(["htb", "rate", &format!("{}mbit", mbit)]),
+        "tc class",
+    )?;
+
+    run_tc_command(
+        Command::new("tc")
+            .args([
+                "filter", "replace", "dev", interface, "parent", "1:", "protocol", "ip",
+            ])
+            .args(["prio", "1", "handle", &mark, "fw", "flowid", &classid]),
+        "tc filter",
+    )?;
+
+    let rule_exists = Command::new("iptables")
+        .arg("-C")
+        .args(iptables_rule)
+        .status()
+        .map_err(|_| "Command iptables not found".to_string())?
+        .success();
+
+    if !rule_exists {
+        run_tc_command(
+            Command::new("iptables").arg("-A").args(iptables_rule),
+            "iptables",
+        )?;
+    }
+
+    set_comment_field(username, "rate_mbit", &mbit.to_string())?;
+
+    Ok(UserStatus {
+        username: username.to_string(),
+        status: format!("Rate limited to {} Mbit/s", mbit),
+    })
+}
+
+/// Builds the iptables rule that marks the packets of a UID with `mark`.
+fn rate_limit_iptables_rule(mark: &str) -> [&str; 11] {
+    [
+        "OUTPUT",
+        "-t",
+        "mangle",
+        "-m",
+        "owner",
+        "--uid-owner",
+        mark,
+        "-j",
+        "MARK",
+        "--set-mark",
+        mark,
+    ]
+}
+
+/// Removes the iptables mark rule, `tc` filter and class that `set_rate_limit` added for a UID.
+///
+/// Removing parts that don't exist fails, which is fine as long as nothing is left, so only a
+/// missing `iptables` or `tc` is an error.
+fn remove_rate_limit_rules(interface: &str, uid: u32) -> Result<(), String> {
+    let classid = format!("1:{:x}", uid);
+    let mark = uid.to_string();
+
+    Command::new("iptables")
+        .arg("-D")
+        .args(rate_limit_iptables_rule(&mark))
+        .status()
+        .map_err(|_| "Command iptables not found".to_string())?;
+    Command::new("tc")
+        .args([
+            "filter", "del", "dev", interface, "parent", "1:", "protocol", "ip",
+        ])
+        .args(["prio", "1", "handle", &mark, "fw"])
+        .status()
+        .map_err(|_| "Command tc not found".to_string())?;
+    Command::new("tc")
+        .args(["class", "del", "dev", interface, "classid", &classid])
+        .status()
+        .map_err(|_| "Command tc not found".to_string())?;
+
+    Ok(())
+}
+
+/// Represents the most recent login of a user.
+pub struct LastLogin {
+    pub username: String,
+    pub time: DateTime<FixedOffset>,
+    pub host: String,
+}
+
+impl LastLogin {
+    /// Formats the login time and host, e.g. `2024-01-01 12:00 from 203.0.113.5`.
+    pub fn summary(&self) -> String {
+        let host = match self.host.as_str() {
+            "" => String::new(),
+            host => format!(" from {}", host),
+        };
+
+        format!(
+            "{} {}{}",
+            display_date(&self.time.format("%Y-%m-%d").to_string()),
+            self.time.format("%H:%M"),
+            host
+        )
+    }
+}
+
+impl fmt::Display for LastLogin {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "username: `{}`\nlast login: `{}`",
+            self.username,
+            self.summary()
+        )
+    }
+}
+
+/// Gets the most recent login of a user from `lastlog -u`.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the `LastLogin`, `None` if the user never logged in, or an error message
+/// if `lastlog` fails or its output can't be parsed.
+pub fn last_login(username: &str) -> Result<Option<LastLogin>, String> {
+    let process_output = Command::new("lastlog")
+        .env("LC_ALL", "C")
+        .arg("-u")
+        .arg(username)
+        .output();
+
+    let output = match process_output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).to_string()
+        }
+        Ok(_) => return Err("Invalid user".to_string()),
+        Err(_) => return Err("Command lastlog not found".to_string()),
+    };
+
+    // The first line is the `Username Port From Latest` header.
+    let line = output.lines().nth(1).ok_or("Unexpected lastlog output")?;
+
+    if line.contains("**Never logged in**") {
+        return Ok(None);
+    }
+
+    // `Latest` is always the last 6 fields, e.g. `Mon Jan  1 12:00:00 +0000 2024`, while `From`
+    // is empty for local logins.
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 7 {
+        return Err("Unexpected lastlog output".to_string());
+    }
+
+    let (user_fields, time_fields) = fields.split_at(fields.len() - 6);
+    let time = DateTime::parse_from_str(&time_fields.join(" "), "%a %b %d %H:%M:%S %z %Y")
+        .map_err(|_| "Unexpected lastlog date".to_string())?;
+
+    Ok(Some(LastLogin {
+        username: username.to_string(),
+        time,
+        host: user_fields.get(2).unwrap_or(&"").to_string(),
+    }))
+}
+
+/// Gets the details of an existing user.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the `UserInfo`, or an error message if the user doesn't exist.
+pub fn get_user_info(username: &str) -> Result<UserInfo, String> {
+    let user = users::get_user_by_name(username).ok_or("Invalid user or group")?;
+
+    let connection_ips = match user_connection_ips(username) {
+        Ok(ips) => Some(ips),
+        Err(err) => {
+            log::warn!("Couldn't get connection IPs of {}: {}", username, err);
+            None
+        }
+    };
+
+    // Frozen and quarantined users are locked too, so check them first.
+    let status = if let Some(frozen_days) = get_comment_field(username, "frozen_days") {
+        format!("frozen with {} days left", frozen_days)
+    } else if get_comment_field(username, "quarantined_from").is_some() {
+        "quarantined".to_string()
+    } else if let Some(days) = get_comment_field(username, "activate_days") {
+        format!("pending activation for {} days", days)
+    } else {
+        match read_shadow_entry(username) {
+            Ok(shadow_entry) if shadow_entry.is_locked() => "locked".to_string(),
+            Ok(_) => "active".to_string(),
+            Err(err) => format!("unknown, {}", err),
+        }
+    };
+
+    let last_login = match last_login(username) {
+        Ok(last_login) => Some(last_login.map(|last_login| last_login.summary())),
+        Err(err) => {
+            log::warn!("Couldn't get last login of {}: {}", username, err);
+            None
+        }
+    };
+
+    Ok(UserInfo {
+        username: username.to_string(),
+        group: get_primary_group(username).unwrap_or_default(),
+        expiry_date: get_chage_exp(username)?.exp_date,
+        shell: user.shell().display().to_string(),
+        status,
+        rate_mbit: get_comment_field(username, "rate_mbit").and_then(|mbit| mbit.parse().ok()),
+        owner: get_user_owner(username),
+        last_login,
+        connection_ips,
+    })
+}
+
+/// Reads the load averages, memory usage and free disk space of the home partition.
+///
+/// # Returns
+///
+/// A `Result` containing the `ServerResources`, or an error message if `/proc` or `df` can't be
+/// read.
+pub fn server_resources() -> Result<ServerResources, String> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg")
+        .map_err(|_| "Couldn't read /proc/loadavg".to_string())?;
+    let loads: Vec<f64> = loadavg
+        .split_whitespace()
+        .take(3)
+        .filter_map(|load| load.parse().ok())
+        .collect();
+    let load_average: [f64; 3] = loads
+        .try_into()
+        .map_err(|_| "Invalid /proc/loadavg".to_string())?;
+
+    let meminfo = std::fs::read_to_string("/proc/meminfo")
+        .map_err(|_| "Couldn't read /proc/meminfo".to_string())?;
+    let meminfo_value = |key: &str| -> u64 {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    };
+
+    let df_output = Command::new("df")
+        .arg("-k")
+        .arg("--output=size,avail")
+        .arg(&config::CONFIG.home_base)
+        .output()
+        .map_err(|_| "Command df not found".to_string())?;
+    let df = String::from_utf8_lossy(&df_output.stdout);
+    let disk: Vec<u64> = df
+        .lines()
+        .nth(1)
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter_map(|value| value.parse().ok())
+        .collect();
+
+    Ok(ServerResources {
+        load_average,
+        memory_total_kb: meminfo_value("MemTotal:"),
+        memory_available_kb: meminfo_value("MemAvailable:"),
+        disk_total_kb: disk.first().copied().unwrap_or(0),
+        disk_available_kb: disk.get(1).copied().unwrap_or(0),
+        managed_users: count_managed_users(),
+    })
+}
+
+/// Represents the disk space and inodes used by the home directory of a user.
+#[derive(Serialize, Clone)]
+pub struct HomeUsage {
+    pub username: String,
+    pub disk_kb: u64,
+    pub inodes: u64,
+}
+
+impl fmt::Display for HomeUsage {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "`{}`: {} MB, {} inodes",
+            self.username,
+            self.disk_kb / 1024,
+            self.inodes
+        )
+    }
+}
+
+/// How long `home_disk_usage` reuses a measurement, since `du` walks the whole directory.
+const HOME_USAGE_CACHE_SECS: u64 = 300;
+
+/// Measurements of `home_disk_usage`, with when each was taken.
+static HOME_USAGE_CACHE: OnceLock<Mutex<HashMap<String, (Instant, HomeUsage)>>> = OnceLock::new();
+
+/// Measures the disk space and inodes used by the home directory of a user with `du`.
+///
+/// Measurements are cached for `HOME_USAGE_CACHE_SECS` seconds.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the `HomeUsage`, or an error message if the user doesn't exist or `du`
+/// fails or is missing.
+pub fn home_disk_usage(username: &str) -> Result<HomeUsage, String> {
+    let cache = HOME_USAGE_CACHE.get_or_init(Default::default);
+
+    if let Some((measured_at, home_usage)) = cache
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(username)
+    {
+        if measured_at.elapsed().as_secs() < HOME_USAGE_CACHE_SECS {
+            return Ok(home_usage.clone());
+        }
+    }
+
+    let home = users::get_user_by_name(username)
+        .ok_or("Invalid user or group".to_string())?
+        .home_dir()
+        .to_path_buf();
+
+    let home_usage = HomeUsage {
+        username: username.to_string(),
+        disk_kb: du_total(&["-sk"], &home)?,
+        inodes: du_total(&["-s", "--inodes"], &home)?,
+    };
+
+    cache
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(username.to_string(), (Instant::now(), home_usage.clone()));
+
+    Ok(home_usage)
+}
+
+/// Runs `du` on a path and parses the total it prints.
+fn du_total(args: &[&str], path: &Path) -> Result<u64, String> {
+    let output = Command::new("du")
+        .args(args)
+        .arg(path)
+        .output()
+        .map_err(|_| "Command du not found".to_string())?;
+
+    // du still prints a total when some files can't be read, so its exit code is ignored.
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .and_then(|total| total.parse().ok())
+        .ok_or_else(|| format!("Couldn't measure {}", path.display()))
+}
+
+/// Counts the active SSH sessions of every user, based on the `sshd` processes they own.
+pub fn get_sessions_count() -> Result<HashMap<String, usize>, String> {
+    let process_output = Command::new("ps")
+        .arg("-eo")
+        .arg("user:64,comm")
+        .arg("--no-headers")
+        .output();
+
+    match process_output {
+        Ok(output) => {
+            let processes = String::from_utf8_lossy(&output.stdout);
+            let mut sessions: HashMap<String, usize> = HashMap::new();
+
+            for line in processes.lines() {
+                let mut fields = line.split_whitespace();
+                if let (Some(user), Some("sshd")) = (fields.next(), fields.next()) {
+                    *sessions.entry(user.to_string()).or_insert(0) += 1;
+                }
+            }
+
+            Ok(sessions)
+        }
+        Err(_) => Err("Command ps not found".to_string()),
     }
 }
 
-fn add_to_time(days: i64) -> String {
-    let now = Local::now().naive_local().date();
-    let future_date = now + Duration::days(days);
-    let formatted_date = future_date.format("%Y-%m-%d").to_string();
-    format!("{}", formatted_date)
+/// Returns the users with the most active SSH sessions.
+///
+/// # Arguments
+///
+/// * `prefix` - The prefix of the usernames to consider.
+/// * `count` - The maximum number of users to return.
+///
+/// # Returns
+///
+/// A `Result` containing the users with at least one session, sorted by sessions descending.
+pub fn top_users_by_sessions(prefix: &str, count: usize) -> Result<Vec<UserSessions>, String> {
+    let mut top_users: Vec<UserSessions> = get_sessions_count()?
+        .into_iter()
+        .filter(|(username, _)| username.starts_with(prefix))
+        .map(|(username, sessions)| UserSessions { username, sessions })
+        .collect();
+
+    top_users.sort_by(|a, b| {
+        b.sessions
+            .cmp(&a.sessions)
+            .then_with(|| a.username.cmp(&b.username))
+    });
+    top_users.truncate(count);
+
+    Ok(top_users)
 }
 
-pub fn get_users_core(prefix: &str, usergroup: Option<&str>) -> Vec<String> {
-    let iter = unsafe { users::all_users() };
-    let mut users_list: Vec<String> = Vec::new();
+/// Returns whether a user is managed by the bot, i.e. it has the active prefix or one of the
+/// `admin_prefixes`, or its primary group is listed in `allowed_groups`.
+pub fn is_managed_user(username: &str) -> bool {
+    let config_file: &config::ConfigFile = &config::CONFIG;
 
-    for user in iter {
-        let username = user.name().to_string_lossy();
-        let groups = user.groups();
+    if username.starts_with(&config_file.active_prefix())
+        || config_file
+            .admin_prefixes
+            .values()
+            .any(|prefix| username.starts_with(prefix))
+    {
+        return true;
+    }
 
-        if username.starts_with(prefix) {
-            if let Some(usergroup) = usergroup {
-                if let Some(groups) = groups {
-                    let group = groups.iter().find(|g| g.name() == usergroup);
-                    if group.is_some() {
-                        users_list.push(username.to_string());
-                    }
-                }
-            } else {
-                users_list.push(username.to_string());
-            }
-        }
+    match (&config_file.allowed_groups, get_primary_group(username)) {
+        (Some(allowed_groups), Some(group)) => allowed_groups.contains(&group),
+        _ => false,
     }
+}
 
-    users_list
+/// Parses a comma-separated list of group names, ignoring empty entries.
+pub fn parse_group_list(groups: &str) -> Vec<String> {
+    groups
+        .split(',')
+        .map(|group| group.trim())
+        .filter(|group| !group.is_empty())
+        .map(|group| group.to_string())
+        .collect()
 }
 
 pub fn gen_password() -> String {
@@ -406,8 +3382,88 @@ pub fn gen_password() -> String {
     format!("SSHMGMT{:05}", random_number)
 }
 
-pub fn hash_password(password: &str) -> String {
-    sha512_crypt::hash_with("$6$mENJascSdtQuhrXH", password).unwrap()
+/// Characters of unambiguous passwords, without look-alikes such as `l`, `1`, `I`, `O` and `0`.
+const UNAMBIGUOUS_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Generates a random password of the given length from characters that are hard to mistype.
+pub fn gen_password_unambiguous(len: usize) -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..len)
+        .map(|_| *UNAMBIGUOUS_ALPHABET.choose(&mut rng).unwrap() as char)
+        .collect()
+}
+
+/// Generates the password of a new user, unambiguous if `unambiguous_password_length` is set.
+pub fn new_password() -> String {
+    match config::CONFIG.unambiguous_password_length {
+        Some(len) => gen_password_unambiguous(len),
+        None => gen_password(),
+    }
+}
+
+/// Hashes a password with the configured `password_hash_scheme`.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    match config::CONFIG.password_hash_scheme {
+        config::PasswordHashScheme::Sha512Crypt => {
+            Ok(sha512_crypt::hash_with("$6$mENJascSdtQuhrXH", password).unwrap())
+        }
+        config::PasswordHashScheme::Yescrypt => {
+            let hash = crypt_with_stdin(
+                Command::new("mkpasswd").args(["--method=yescrypt", "--stdin"]),
+                &format!("{}\n", password),
+            )
+            .map_err(|e| format!("Couldn't hash password with mkpasswd: {}", e))?;
+
+            if !hash.starts_with("$y$") {
+                return Err(format!("mkpasswd returned an invalid hash: {}", hash));
+            }
+
+            Ok(hash)
+        }
+    }
+}
+
+/// Runs a hashing command with its input on stdin, so passwords don't show up in the process
+/// list, and returns its trimmed output.
+fn crypt_with_stdin(command: &mut Command, input: &str) -> Result<String, String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(input.as_bytes())
+            .map_err(|e| e.to_string())?;
+    }
+
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(command_error_output(&output));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Checks a password against a crypt hash. Hashes `pwhash` doesn't support, like yescrypt, are
+/// checked with the system's `crypt` through `perl`.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if !hash.starts_with("$y$") {
+        return pwhash::unix::verify(password, hash);
+    }
+
+    crypt_with_stdin(
+        Command::new("perl").args([
+            "-e",
+            r#"chomp(my $password = <STDIN>); chomp(my $hash = <STDIN>); print crypt($password, $hash)"#,
+        ]),
+        &format!("{}\n{}\n", password, hash),
+    )
+    .is_ok_and(|computed| computed == hash)
 }
 
 fn format_exp_date(exp_date: &str) -> Result<String, String> {
@@ -426,6 +3482,20 @@ fn compress_data(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
     encoder.finish()
 }
 
+/// Encodes an ASCII string the way Kryo does, by setting the high bit of its last byte.
+fn kryo_ascii(value: &str, field: &str) -> Result<Vec<u8>, String> {
+    let last_char = value
+        .chars()
+        .last()
+        .ok_or_else(|| format!("Empty {}", field))?;
+
+    let mut bytes: Vec<u8> = value.as_bytes().to_vec();
+    bytes.pop();
+    bytes.push(last_char as u8 + 128);
+
+    Ok(bytes)
+}
+
 /// Generates a Sagernet link for SSH connection based on user and server details.
 ///
 /// # Arguments
@@ -439,7 +3509,8 @@ fn compress_data(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
 ///
 /// # Returns
 ///
-/// A Sagernet link encoded for SSH connection.
+/// A Sagernet link encoded for SSH connection, or an error message if the server address,
+/// username or password is empty.
 pub fn sagernet_link_generator(
     server_address: &str,
     port: u32,
@@ -447,14 +3518,10 @@ pub fn sagernet_link_generator(
     password: &str,
     location: &str,
     exp_date: &str,
-) -> String {
+) -> Result<String, String> {
     let mut kryo_bytes: Vec<u8> = b"\x00\x00\x00\x00".to_vec();
 
-    let mut server_address_bytes: Vec<u8> = server_address.as_bytes().to_vec();
-    server_address_bytes.pop();
-    server_address_bytes.push(server_address.chars().last().unwrap() as u8 + 128);
-
-    kryo_bytes.extend(server_address_bytes);
+    kryo_bytes.extend(kryo_ascii(server_address, "server address")?);
 
     let mut port_bytes = [0u8; 2];
     LittleEndian::write_u16(&mut port_bytes, port as u16);
@@ -462,19 +3529,11 @@ pub fn sagernet_link_generator(
     kryo_bytes.extend(port_bytes);
     kryo_bytes.extend(b"\x00\x00");
 
-    let mut username_bytes: Vec<u8> = username.as_bytes().to_vec();
-    username_bytes.pop();
-    username_bytes.push(username.chars().last().unwrap() as u8 + 128);
-
-    kryo_bytes.extend(username_bytes);
+    kryo_bytes.extend(kryo_ascii(username, "username")?);
 
     kryo_bytes.extend(b"\x01\x00\x00\x00");
 
-    let mut password_bytes: Vec<u8> = password.as_bytes().to_vec();
-    password_bytes.pop();
-    password_bytes.push(password.chars().last().unwrap() as u8 + 128);
-
-    kryo_bytes.extend(password_bytes);
+    kryo_bytes.extend(kryo_ascii(password, "password")?);
 
     kryo_bytes.extend(b"\x81\x01\x00\x00\x00\xa1");
 
@@ -483,11 +3542,501 @@ pub fn sagernet_link_generator(
     kryo_bytes.extend(title.as_bytes());
     kryo_bytes.extend(b"\x00\x00\x00\x00");
 
-    let zlib_compressed = compress_data(&kryo_bytes).unwrap();
+    let zlib_compressed = compress_data(&kryo_bytes).map_err(|e| e.to_string())?;
 
     let base64_urlsafe = base64_url::encode(&zlib_compressed);
 
-    format!("sn://ssh?{}", base64_urlsafe)
+    Ok(format!("sn://ssh?{}", base64_urlsafe))
+}
+
+/// Represents the fields decoded from a Sagernet link.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct LinkFields {
+    pub server_address: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub title: String,
+}
+
+impl fmt::Display for LinkFields {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "server: `{}`\nport: `{}`\nusername: `{}`\npassword: `{}`\ntitle: `{}`",
+            self.server_address, self.port, self.username, self.password, self.title
+        )
+    }
+}
+
+/// Reads a Kryo ASCII string, whose last byte has its high bit set, advancing `bytes` past it.
+fn read_kryo_ascii(bytes: &mut &[u8], field: &str) -> Result<String, String> {
+    let end = bytes
+        .iter()
+        .position(|byte| byte & 0x80 != 0)
+        .ok_or_else(|| format!("Unterminated {}", field))?;
+
+    let mut value = bytes[..=end].to_vec();
+    value[end] &= 0x7f;
+    *bytes = &bytes[end + 1..];
+
+    String::from_utf8(value).map_err(|_| format!("Invalid {}", field))
+}
+
+/// Checks that `bytes` starts with `expected`, advancing `bytes` past it.
+fn read_kryo_marker(bytes: &mut &[u8], expected: &[u8], field: &str) -> Result<(), String> {
+    match bytes.strip_prefix(expected) {
+        Some(rest) => {
+            *bytes = rest;
+            Ok(())
+        }
+        None => Err(format!("Unexpected bytes before {}", field)),
+    }
+}
+
+/// Decodes a Sagernet SSH link built by `sagernet_link_generator`.
+///
+/// The link is `sn://ssh?` followed by URL-safe base64 of zlib-compressed Kryo data:
+///
+/// * 4 zero bytes.
+/// * The server address as a Kryo ASCII string.
+/// * The port as a little-endian `u16`, then 2 zero bytes.
+/// * The username as a Kryo ASCII string, then `01 00 00 00`.
+/// * The password as a Kryo ASCII string, then `81 01 00 00 00 a1`.
+/// * The title as raw bytes, then 4 zero bytes.
+///
+/// # Arguments
+///
+/// * `link` - The Sagernet link.
+///
+/// # Returns
+///
+/// A `Result` containing the decoded `LinkFields`, or an error message if the link is malformed.
+pub fn parse_sagernet_link(link: &str) -> Result<LinkFields, String> {
+    let encoded = link
+        .strip_prefix("sn://ssh?")
+        .ok_or("Not a Sagernet SSH link")?;
+
+    let zlib_compressed = base64_url::decode(encoded).map_err(|e| e.to_string())?;
+
+    let mut kryo_bytes: Vec<u8> = Vec::new();
+    ZlibDecoder::new(zlib_compressed.as_slice())
+        .read_to_end(&mut kryo_bytes)
+        .map_err(|e| e.to_string())?;
+
+    let mut bytes = kryo_bytes.as_slice();
+
+    read_kryo_marker(&mut bytes, b"\x00\x00\x00\x00", "server address")?;
+    let server_address = read_kryo_ascii(&mut bytes, "server address")?;
+
+    if bytes.len() < 2 {
+        return Err("Missing port".to_string());
+    }
+    let port = LittleEndian::read_u16(&bytes[..2]);
+    bytes = &bytes[2..];
+
+    read_kryo_marker(&mut bytes, b"\x00\x00", "username")?;
+    let username = read_kryo_ascii(&mut bytes, "username")?;
+
+    read_kryo_marker(&mut bytes, b"\x01\x00\x00\x00", "password")?;
+    let password = read_kryo_ascii(&mut bytes, "password")?;
+
+    read_kryo_marker(&mut bytes, b"\x81\x01\x00\x00\x00\xa1", "title")?;
+    let title = bytes
+        .strip_suffix(b"\x00\x00\x00\x00")
+        .ok_or("Unterminated title")?;
+    let title = String::from_utf8(title.to_vec()).map_err(|_| "Invalid title".to_string())?;
+
+    Ok(LinkFields {
+        server_address,
+        port,
+        username,
+        password,
+        title,
+    })
+}
+
+/// Generates a Sagernet link for a user and checks that decoding it gives back the same fields.
+///
+/// A random password is used, since the user's real one can't be read back from `/etc/shadow`.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+///
+/// # Returns
+///
+/// A `Result` containing the decoded `LinkFields`, or an error message if generating or decoding
+/// the link fails or a field doesn't match.
+pub fn verify_sagernet_link(username: &str) -> Result<LinkFields, String> {
+    let config_file: &config::ConfigFile = &config::CONFIG;
+    let exp_date = get_chage_exp(username)?.exp_date;
+    let port = config_file.ports[0];
+    let password = gen_password();
+
+    let link = sagernet_link_generator(
+        &config_file.server_address,
+        port,
+        username,
+        &password,
+        &config_file.location,
+        &exp_date,
+    )?;
+
+    let expected = LinkFields {
+        server_address: config_file.server_address.clone(),
+        port: port as u16,
+        username: username.to_string(),
+        password,
+        title: format!(
+            "SpeedPing({}) {} {}",
+            username, config_file.location, exp_date
+        ),
+    };
+
+    let link_fields = parse_sagernet_link(&link)?;
+
+    if link_fields != expected {
+        return Err(format!(
+            "Link doesn't round-trip, expected {:?} but decoded {:?}",
+            expected, link_fields
+        ));
+    }
+
+    Ok(link_fields)
+}
+
+/// Checks a password against the hash in `/etc/shadow`. Fails when the entry can't be read, so
+/// an unverified password is never handed out.
+fn check_password(username: &str, password: &str) -> Result<(), String> {
+    let shadow_entry = read_shadow_entry(username)?;
+    let hash = shadow_entry.password_hash.trim_start_matches('!');
+    if !verify_password(password, hash) {
+        return Err("Wrong password".to_string());
+    }
+
+    Ok(())
+}
+
+/// Glyphs of the printable ASCII characters in a classic 5x7 font, one byte per column with the
+/// top row in the lowest bit.
+const FONT_5X7: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5F, 0x00, 0x00], // '!'
+    [0x00, 0x07, 0x00, 0x07, 0x00], // '"'
+    [0x14, 0x7F, 0x14, 0x7F, 0x14], // '#'
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], // '$'
+    [0x23, 0x13, 0x08, 0x64, 0x62], // '%'
+    [0x36, 0x49, 0x55, 0x22, 0x50], // '&'
+    [0x00, 0x05, 0x03, 0x00, 0x00], // "'"
+    [0x00, 0x1C, 0x22, 0x41, 0x00], // '('
+    [0x00, 0x41, 0x22, 0x1C, 0x00], // ')'
+    [0x14, 0x08, 0x3E, 0x08, 0x14], // '*'
+    [0x08, 0x08, 0x3E, 0x08, 0x08], // '+'
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ','
+    [0x08, 0x08, 0x08, 0x08, 0x08], // '-'
+    [0x00, 0x60, 0x60, 0x00, 0x00], // '.'
+    [0x20, 0x10, 0x08, 0x04, 0x02], // '/'
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], // '0'
+    [0x00, 0x42, 0x7F, 0x40, 0x00], // '1'
+    [0x42, 0x61, 0x51, 0x49, 0x46], // '2'
+    [0x21, 0x41, 0x45, 0x4B, 0x31], // '3'
+    [0x18, 0x14, 0x12, 0x7F, 0x10], // '4'
+    [0x27, 0x45, 0x45, 0x45, 0x39], // '5'
+    [0x3C, 0x4A, 0x49, 0x49, 0x30], // '6'
+    [0x01, 0x71, 0x09, 0x05, 0x03], // '7'
+    [0x36, 0x49, 0x49, 0x49, 0x36], // '8'
+    [0x06, 0x49, 0x49, 0x29, 0x1E], // '9'
+    [0x00, 0x36, 0x36, 0x00, 0x00], // ':'
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ';'
+    [0x08, 0x14, 0x22, 0x41, 0x00], // '<'
+    [0x14, 0x14, 0x14, 0x14, 0x14], // '='
+    [0x00, 0x41, 0x22, 0x14, 0x08], // '>'
+    [0x02, 0x01, 0x51, 0x09, 0x06], // '?'
+    [0x32, 0x49, 0x79, 0x41, 0x3E], // '@'
+    [0x7E, 0x11, 0x11, 0x11, 0x7E], // 'A'
+    [0x7F, 0x49, 0x49, 0x49, 0x36], // 'B'
+    [0x3E, 0x41, 0x41, 0x41, 0x22], // 'C'
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], // 'D'
+    [0x7F, 0x49, 0x49, 0x49, 0x41], // 'E'
+    [0x7F, 0x09, 0x09, 0x09, 0x01], // 'F'
+    [0x3E, 0x41, 0x49, 0x49, 0x7A], // 'G'
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], // 'H'
+    [0x00, 0x41, 0x7F, 0x41, 0x00], // 'I'
+    [0x20, 0x40, 0x41, 0x3F, 0x01], // 'J'
+    [0x7F, 0x08, 0x14, 0x22, 0x41], // 'K'
+    [0x7F, 0x40, 0x40, 0x40, 0x40], // 'L'
+    [0x7F, 0x02, 0x0C, 0x02, 0x7F], // 'M'
+    [0x7F, 0x04, 0x08, 0x10, 0x7F], // 'N'
+    [0x3E, 0x41, 0x41, 0x41, 0x3E], // 'O'
+    [0x7F, 0x09, 0x09, 0x09, 0x06], // 'P'
+    [0x3E, 0x41, 0x51, 0x21, 0x5E], // 'Q'
+    [0x7F, 0x09, 0x19, 0x29, 0x46], // 'R'
+    [0x46, 0x49, 0x49, 0x49, 0x31], // 'S'
+    [0x01, 0x01, 0x7F, 0x01, 0x01], // 'T'
+    [0x3F, 0x40, 0x40, 0x40, 0x3F], // 'U'
+    [0x1F, 0x20, 0x40, 0x20, 0x1F], // 'V'
+    [0x3F, 0x40, 0x38, 0x40, 0x3F], // 'W'
+    [0x63, 0x14, 0x08, 0x14, 0x63], // 'X'
+    [0x07, 0x08, 0x70, 0x08, 0x07], // 'Y'
+    [0x61, 0x51, 0x49, 0x45, 0x43], // 'Z'
+    [0x00, 0x7F, 0x41, 0x41, 0x00], // '['
+    [0x02, 0x04, 0x08, 0x10, 0x20], // '\\'
+    [0x00, 0x41, 0x41, 0x7F, 0x00], // ']'
+    [0x04, 0x02, 0x01, 0x02, 0x04], // '^'
+    [0x40, 0x40, 0x40, 0x40, 0x40], // '_'
+    [0x00, 0x01, 0x02, 0x04, 0x00], // '`'
+    [0x20, 0x54, 0x54, 0x54, 0x78], // 'a'
+    [0x7F, 0x48, 0x44, 0x44, 0x38], // 'b'
+    [0x38, 0x44, 0x44, 0x44, 0x20], // 'c'
+    [0x38, 0x44, 0x44, 0x48, 0x7F], // 'd'
+    [0x38, 0x54, 0x54, 0x54, 0x18], // 'e'
+    [0x08, 0x7E, 0x09, 0x01, 0x02], // 'f'
+    [0x0C, 0x52, 0x52, 0x52, 0x3E], // 'g'
+    [0x7F, 0x08, 0x04, 0x04, 0x78], // 'h'
+    [0x00, 0x44, 0x7D, 0x40, 0x00], // 'i'
+    [0x20, 0x40, 0x44, 0x3D, 0x00], // 'j'
+    [0x7F, 0x10, 0x28, 0x44, 0x00], // 'k'
+    [0x00, 0x41, 0x7F, 0x40, 0x00], // 'l'
+    [0x7C, 0x04, 0x18, 0x04, 0x78], // 'm'
+    [0x7C, 0x08, 0x04, 0x04, 0x78], // 'n'
+    [0x38, 0x44, 0x44, 0x44, 0x38], // 'o'
+    [0x7C, 0x14, 0x14, 0x14, 0x08], // 'p'
+    [0x08, 0x14, 0x14, 0x18, 0x7C], // 'q'
+    [0x7C, 0x08, 0x04, 0x04, 0x08], // 'r'
+    [0x48, 0x54, 0x54, 0x54, 0x20], // 's'
+    [0x04, 0x3F, 0x44, 0x40, 0x20], // 't'
+    [0x3C, 0x40, 0x40, 0x20, 0x7C], // 'u'
+    [0x1C, 0x20, 0x40, 0x20, 0x1C], // 'v'
+    [0x3C, 0x40, 0x30, 0x40, 0x3C], // 'w'
+    [0x44, 0x28, 0x10, 0x28, 0x44], // 'x'
+    [0x0C, 0x50, 0x50, 0x50, 0x3C], // 'y'
+    [0x44, 0x64, 0x54, 0x4C, 0x44], // 'z'
+    [0x00, 0x08, 0x36, 0x41, 0x00], // '{'
+    [0x00, 0x00, 0x7F, 0x00, 0x00], // '|'
+    [0x00, 0x41, 0x36, 0x08, 0x00], // '}'
+    [0x08, 0x04, 0x08, 0x10, 0x08], // '~'
+];
+
+/// Draws ASCII text in black, each font pixel being `scale` pixels wide. Characters without a
+/// glyph are drawn as `?`.
+fn draw_text(image: &mut RgbImage, text: &str, x: u32, y: u32, scale: u32) {
+    for (index, character) in text.chars().enumerate() {
+        let glyph = match character {
+            ' '..='~' => FONT_5X7[character as usize - 0x20],
+            _ => FONT_5X7['?' as usize - 0x20],
+        };
+        let character_x = x + index as u32 * 6 * scale;
+
+        for (column, bits) in glyph.iter().enumerate() {
+            for row in 0..7 {
+                if bits >> row & 1 == 0 {
+                    continue;
+                }
+
+                for dx in 0..scale {
+                    for dy in 0..scale {
+                        let pixel_x = character_x + column as u32 * scale + dx;
+                        let pixel_y = y + row * scale + dy;
+
+                        if pixel_x < image.width() && pixel_y < image.height() {
+                            image.put_pixel(pixel_x, pixel_y, Rgb([0, 0, 0]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Composes a printable card with the QR code of a link above the server, username, password and
+/// expiry date of a user, in black on white.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `password` - The password of the SSH user.
+/// * `link` - The link encoded in the QR code.
+///
+/// # Returns
+///
+/// A `Result` containing the PNG bytes of the card, or an error message if the expiry date can't
+/// be read or the image can't be encoded.
+pub fn generate_credentials_card(
+    username: &str,
+    password: &str,
+    link: &str,
+) -> Result<Vec<u8>, String> {
+    const MARGIN: u32 = 40;
+    const SCALE: u32 = 4;
+    const LINE_HEIGHT: u32 = 10 * SCALE;
+
+    let config_file: &config::ConfigFile = &config::CONFIG;
+    let exp_date = get_chage_exp(username)?.exp_date;
+
+    let qr_image = QrCode::new(link.as_bytes())
+        .map_err(|e| e.to_string())?
+        .render::<Rgb<u8>>()
+        .max_dimensions(400, 400)
+        .build();
+
+    let lines = [
+        format!("Server: {}", config_file.server_address),
+        format!("Port: {}", config_file.ports[0]),
+        format!("Username: {}", username),
+        format!("Password: {}", password),
+        format!("Expires: {}", display_date(&exp_date)),
+    ];
+
+    let text_width = lines
+        .iter()
+        .map(|line| line.chars().count() as u32 * 6 * SCALE)
+        .max()
+        .unwrap_or(0);
+    let width = qr_image.width().max(text_width) + 2 * MARGIN;
+    let height = MARGIN + qr_image.height() + MARGIN + lines.len() as u32 * LINE_HEIGHT + MARGIN;
+
+    let mut card = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+    image::imageops::overlay(&mut card, &qr_image, (width - qr_image.width()) / 2, MARGIN);
+
+    let text_y = MARGIN + qr_image.height() + MARGIN;
+    for (index, line) in lines.iter().enumerate() {
+        draw_text(
+            &mut card,
+            line,
+            MARGIN,
+            text_y + index as u32 * LINE_HEIGHT,
+            SCALE,
+        );
+    }
+
+    let mut image_bytes: Vec<u8> = Vec::new();
+    DynamicImage::ImageRgb8(card)
+        .write_to(&mut image_bytes, ImageOutputFormat::Png)
+        .map_err(|e| format!("Couldn't encode card: {}", e))?;
+
+    Ok(image_bytes)
+}
+
+/// Generates the printable credentials card of a user, after checking its password.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `password` - The password of the SSH user, checked against `/etc/shadow`.
+///
+/// # Returns
+///
+/// A `Result` containing the PNG bytes of the card, or an error message if the password is wrong
+/// or can't be checked, or generating the card fails.
+pub fn generate_card(username: &str, password: &str) -> Result<Vec<u8>, String> {
+    check_password(username, password)?;
+
+    let config_file: &config::ConfigFile = &config::CONFIG;
+    let exp_date = get_chage_exp(username)?.exp_date;
+
+    let sagernet_link = sagernet_link_generator(
+        &config_file.server_address,
+        config_file.ports[0],
+        username,
+        password,
+        &config_file.location,
+        &exp_date,
+    )?;
+
+    generate_credentials_card(username, password, &sagernet_link)
+}
+
+/// Generates a zip archive with everything a customer needs to connect: the Sagernet link, its QR
+/// code, an `~/.ssh/config` snippet and a README.
+///
+/// # Arguments
+///
+/// * `username` - The username of the SSH user.
+/// * `password` - The password of the SSH user, checked against `/etc/shadow`.
+///
+/// # Returns
+///
+/// A `Result` containing the bytes of the zip archive, or an error message if the password is
+/// wrong or can't be checked, or generating the archive fails.
+pub fn generate_bundle(username: &str, password: &str) -> Result<Vec<u8>, String> {
+    check_password(username, password)?;
+
+    let config_file: &config::ConfigFile = &config::CONFIG;
+    let exp_date = get_chage_exp(username)?.exp_date;
+    let port = config_file.ports[0];
+
+    let sagernet_link = sagernet_link_generator(
+        &config_file.server_address,
+        port,
+        username,
+        password,
+        &config_file.location,
+        &exp_date,
+    )?;
+    let qr_bytes = encode_qr_code_to_image_bytes(&sagernet_link);
+
+    let ssh_config = format!(
+        "Host {}\n    HostName {}\n    Port {}\n    User {}\n",
+        config_file.location, config_file.server_address, port, username
+    );
+
+    let readme = format!(
+        "username: {}\npassword: {}\nexpiry date: {}\n{}\n\n\
+         Import sagernet.txt or scan qrcode.png in Sagernet, or append ssh_config to ~/.ssh/config \
+         and run `ssh -N -D 1080 {}` for a SOCKS proxy on port 1080.\n",
+        username,
+        password,
+        display_date(&exp_date),
+        config_file,
+        config_file.location
+    );
+
+    let qr_svg = encode_qr_code_to_svg(&sagernet_link)?;
+
+    let files: [(&str, &[u8]); 5] = [
+        ("sagernet.txt", sagernet_link.as_bytes()),
+        ("qrcode.png", &qr_bytes),
+        ("qrcode.svg", qr_svg.as_bytes()),
+        ("ssh_config", ssh_config.as_bytes()),
+        ("README.txt", readme.as_bytes()),
+    ];
+
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    let mut zip = zip::ZipWriter::new(&mut buffer);
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    for (name, content) in files {
+        zip.start_file(name, options).map_err(|e| e.to_string())?;
+        zip.write_all(content).map_err(|e| e.to_string())?;
+    }
+
+    zip.finish().map_err(|e| e.to_string())?;
+    drop(zip);
+
+    Ok(buffer.into_inner())
+}
+
+/// Generates a scalable SVG QR code with the provided text, in the same colors as the PNG one.
+///
+/// # Arguments
+///
+/// * `text` - The text to be encoded in the QR code.
+///
+/// # Returns
+///
+/// A `Result` containing the SVG document, or an error message if the text doesn't fit in a QR
+/// code.
+pub fn encode_qr_code_to_svg(text: &str) -> Result<String, String> {
+    let qrcode = QrCode::new(text.as_bytes()).map_err(|e| e.to_string())?;
+
+    Ok(qrcode
+        .render::<svg::Color>()
+        .dark_color(svg::Color("#7bff06"))
+        .light_color(svg::Color("#1c201f"))
+        .min_dimensions(550, 550)
+        .build())
 }
 
 /// Generates a QR code image with the provided text.
@@ -518,3 +4067,310 @@ pub fn encode_qr_code_to_image_bytes(text: &str) -> Vec<u8> {
     image_bytes
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_map_to_messages() {
+        let error_code_map = HashMap::new();
+        let message = |code| code_to_err(Some(code), &error_code_map);
+
+        assert_eq!(message(0), None);
+        assert_eq!(message(2).as_deref(), Some("Invalid command syntax"));
+        assert_eq!(message(4).as_deref(), Some("UID already in use"));
+        assert_eq!(message(10).as_deref(), Some("Can't update group file"));
+        assert_eq!(
+            message(12).as_deref(),
+            Some("Can't create home directory, check disk space and /etc/skel")
+        );
+        assert_eq!(message(13).as_deref(), Some("Can't create mail spool"));
+        assert_eq!(
+            message(14).as_deref(),
+            Some("Can't update SELinux user mapping")
+        );
+        assert_eq!(message(42).as_deref(), Some("Unexpected error"));
+        assert_eq!(
+            code_to_err(None, &error_code_map).as_deref(),
+            Some("Process terminated")
+        );
+    }
+
+    #[test]
+    fn exit_code_messages_can_be_overridden() {
+        let error_code_map = HashMap::from([(9, "Taken".to_string())]);
+
+        assert_eq!(
+            code_to_err(Some(9), &error_code_map).as_deref(),
+            Some("Taken")
+        );
+        assert_eq!(code_to_err(Some(0), &error_code_map), None);
+    }
+
+    #[test]
+    fn sagernet_link_rejects_empty_fields() {
+        let link = |server: &str, username: &str, password: &str| {
+            sagernet_link_generator(server, 22, username, password, "DE", "2024-01-31")
+        };
+
+        assert_eq!(
+            link("", "user001", "secret"),
+            Err("Empty server address".to_string())
+        );
+        assert_eq!(
+            link("example.com", "", "secret"),
+            Err("Empty username".to_string())
+        );
+        assert_eq!(
+            link("example.com", "user001", ""),
+            Err("Empty password".to_string())
+        );
+    }
+
+    #[test]
+    fn sagernet_link_round_trips() {
+        let link =
+            sagernet_link_generator("example.com", 2222, "user001", "s3cret", "DE", "2024-01-31")
+                .unwrap();
+
+        assert_eq!(
+            parse_sagernet_link(&link),
+            Ok(LinkFields {
+                server_address: "example.com".to_string(),
+                port: 2222,
+                username: "user001".to_string(),
+                password: "s3cret".to_string(),
+                title: "SpeedPing(user001) DE 2024-01-31".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn days_must_be_positive() {
+        assert_eq!(
+            validate_days(0, None),
+            Err("Days must be at least 1".to_string())
+        );
+        assert_eq!(
+            validate_days(-5, None),
+            Err("Days must be at least 1".to_string())
+        );
+        assert!(validate_days(1, None).is_ok());
+    }
+
+    #[test]
+    fn days_must_be_within_max_expiry_days() {
+        let max_expiry_days = 365;
+
+        assert!(validate_days(max_expiry_days, Some(max_expiry_days)).is_ok());
+        assert_eq!(
+            validate_days(max_expiry_days + 1, Some(max_expiry_days)),
+            Err("Days must be at most 365".to_string())
+        );
+    }
+
+    #[test]
+    fn unambiguous_passwords_avoid_confusable_characters() {
+        for len in [8, 12, 32] {
+            for _ in 0..200 {
+                let password = gen_password_unambiguous(len);
+
+                assert_eq!(password.len(), len);
+                assert!(
+                    !password.contains(['l', '1', 'I', 'O', '0']),
+                    "{password} has a confusable character"
+                );
+            }
+        }
+    }
+
+    const SHADOW_FIXTURE: &str = "root:*:19000:0:99999:7:::
+user001:$6$salt$hash:19000:0:99999:7::19753:
+user002:!$6$salt$hash:19000:0:99999:7:::
+broken:$6$salt$hash:19000
+badexpiry:$6$salt$hash:19000:0:99999:7::soon:
+";
+
+    #[test]
+    fn shadow_entry_is_parsed() {
+        let shadow_entry = parse_shadow_entry(SHADOW_FIXTURE, "user001").unwrap();
+
+        assert_eq!(shadow_entry.username, "user001");
+        assert_eq!(shadow_entry.password_hash, "$6$salt$hash");
+        assert_eq!(shadow_entry.expire_days, Some(19753));
+        assert!(!shadow_entry.is_locked());
+    }
+
+    #[test]
+    fn shadow_entry_without_expiry_is_locked() {
+        let shadow_entry = parse_shadow_entry(SHADOW_FIXTURE, "user002").unwrap();
+
+        assert_eq!(shadow_entry.expire_days, None);
+        assert!(shadow_entry.is_locked());
+    }
+
+    #[test]
+    fn shadow_entry_errors() {
+        assert_eq!(
+            parse_shadow_entry(SHADOW_FIXTURE, "missing").err(),
+            Some("User missing isn't in /etc/shadow".to_string())
+        );
+        assert_eq!(
+            parse_shadow_entry(SHADOW_FIXTURE, "broken").err(),
+            Some("Malformed /etc/shadow line of broken".to_string())
+        );
+        assert_eq!(
+            parse_shadow_entry(SHADOW_FIXTURE, "badexpiry").err(),
+            Some("Malformed /etc/shadow line of badexpiry".to_string())
+        );
+    }
+
+    #[test]
+    fn arguments_starting_with_dash_are_rejected() {
+        assert_eq!(
+            check_argument("-p"),
+            Err("Invalid argument -p, it can't start with -".to_string())
+        );
+        assert!(check_argument("--help").is_err());
+        assert!(check_argument("max2").is_ok());
+        assert!(check_argument("user-001").is_ok());
+    }
+
+    #[test]
+    fn passwd_diff_finds_untracked_and_renamed_users() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash
+user001:x:1001:1001:expiry=2024-01-31,owner=1:/home/user001:/bin/rbash
+user002:x:1002:1002::/home/user002:/bin/rbash
+alice:x:1003:1003:expiry=2024-02-01:/home/alice:/bin/rbash
+bob_001:x:1004:1004:expiry=2024-02-01:/home/bob_001:/bin/rbash
+";
+        let diff = diff_passwd(passwd, "user", &["user".to_string(), "bob_".to_string()]);
+
+        assert_eq!(diff.untracked, ["user002"]);
+        assert_eq!(diff.outside_prefix, ["alice"]);
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn logins_range_accepts_boundaries() {
+        assert!(check_logins_range("max1", Some(1), Some(10)).is_ok());
+        assert!(check_logins_range("max10", Some(1), Some(10)).is_ok());
+    }
+
+    #[test]
+    fn logins_range_rejects_out_of_range() {
+        assert_eq!(
+            check_logins_range("max0", Some(1), Some(10)),
+            Err("Max logins must be at least 1".to_string())
+        );
+        assert_eq!(
+            check_logins_range("max11", Some(1), Some(10)),
+            Err("Max logins must be at most 10".to_string())
+        );
+    }
+
+    #[test]
+    fn logins_range_rejects_overflowing_groups() {
+        assert_eq!(
+            check_logins_range("max99999999999", Some(1), Some(10)),
+            Err("Max logins must be at most 10".to_string())
+        );
+        assert!(check_logins_range("max99999999999", None, None).is_err());
+        assert!(check_logins_range("max4294967295", None, None).is_ok());
+        assert!(check_logins_range("max4294967296", None, None).is_err());
+    }
+
+    #[test]
+    fn parse_max_logins_requires_digits() {
+        assert_eq!(parse_max_logins("max2"), Some(2));
+        assert_eq!(parse_max_logins("max002"), Some(2));
+        assert_eq!(parse_max_logins("max+9999"), None);
+        assert_eq!(parse_max_logins("max-1"), None);
+        assert_eq!(parse_max_logins("max 2"), None);
+        assert_eq!(parse_max_logins("max"), None);
+        assert_eq!(parse_max_logins("max4294967296"), None);
+        assert_eq!(max_logins_label("max+9999"), "unknown");
+    }
+
+    #[test]
+    fn logins_range_ignores_other_groups() {
+        assert!(check_logins_range("users", Some(1), Some(10)).is_ok());
+        assert!(check_logins_range("maxi", Some(1), Some(10)).is_ok());
+    }
+
+    /// Builds the contents of an `/etc/passwd` with `count` users named `user001` onwards.
+    fn large_passwd(count: usize) -> String {
+        let mut passwd = String::from("root:x:0:0:root:/root:/bin/bash\n");
+
+        for i in 1..=count {
+            passwd.push_str(&format!(
+                "user{:03}:x:{}:{}:expiry=2024-02-01:/home/user{:03}:/bin/rbash\n",
+                i,
+                1000 + i,
+                1000 + i,
+                i
+            ));
+        }
+
+        passwd
+    }
+
+    #[test]
+    fn max_passwd_suffix_ignores_date_prefixed_users() {
+        let passwd = "\
+user001:x:1001:1001::/home/user001:/bin/rbash
+user007:x:1002:1002::/home/user007:/bin/rbash
+user24013102:x:1003:1003::/home/user24013102:/bin/rbash
+userabc:x:1004:1004::/home/userabc:/bin/rbash
+user:x:1005:1005::/home/user:/bin/rbash
+";
+        assert_eq!(max_passwd_suffix(passwd, "user"), 7);
+        assert_eq!(max_passwd_suffix(passwd, "user240131"), 2);
+        assert_eq!(max_passwd_suffix(passwd, "nobody"), 0);
+        assert_eq!(count_passwd_prefix(passwd, "user"), 5);
+    }
+
+    #[test]
+    fn max_passwd_suffix_reads_large_passwd_files() {
+        let passwd = large_passwd(5000);
+
+        assert_eq!(max_passwd_suffix(&passwd, "user"), 5000);
+        assert_eq!(count_passwd_prefix(&passwd, "user"), 5000);
+    }
+
+    /// Times the suffix and count scans over a large passwd file against collecting the usernames
+    /// first like `get_users_core` does. Run with `cargo test -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_passwd_scans() {
+        let passwd = large_passwd(20000);
+        let runs = 100;
+
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            let usernames: Vec<String> = passwd_usernames(&passwd)
+                .filter(|username| username.starts_with("user"))
+                .map(|username| username.to_string())
+                .collect();
+            assert_eq!(usernames.len(), 20000);
+        }
+        let collected = start.elapsed() / runs;
+
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            assert_eq!(count_passwd_prefix(&passwd, "user"), 20000);
+        }
+        let counted = start.elapsed() / runs;
+
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            assert_eq!(max_passwd_suffix(&passwd, "user"), 20000);
+        }
+        let max_suffix = start.elapsed() / runs;
+
+        println!(
+            "20000 users: collecting {:?}, counting {:?}, max suffix {:?} per scan",
+            collected, counted, max_suffix
+        );
+    }
+}
@@ -1,285 +1,2833 @@
-use lazy_static::lazy_static;
-use lib::config;
-use teloxide::types::{CallbackQuery, InputFile, ParseMode};
-use teloxide::{prelude::*, utils::command::BotCommands};
+use lib::config::{self, CONFIG};
+use lib::{ExpiryFilter, GroupFilter};
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use teloxide::types::{CallbackQuery, InputFile, MessageId, ParseMode};
+use teloxide::utils::command::{BotCommands, ParseError};
 use teloxide_core::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::MissedTickBehavior;
 
-lazy_static! {
-    static ref CONFIG: config::ConfigFile =
-        config::ConfigFile::load().unwrap_or_else(|_| panic!("Couldn't load config file!"));
+#[tokio::main]
+async fn main() {
+    pretty_env_logger::init();
+    log::info!("Starting command bot...");
+
+    if CONFIG.api_enabled {
+        tokio::spawn(lib::api::serve(CONFIG.clone()));
+    }
+
+    if CONFIG.admin_list_file.is_some() {
+        tokio::spawn(admin_list_reload_task());
+    }
+
+    if CONFIG.geoip_zone_dir.is_some() {
+        for err in lib::geoblock::restore_blocks() {
+            log::error!("{err}");
+        }
+    }
+
+    let bot = Bot::new(&CONFIG.bot_token);
+
+    let (log_sender, log_receiver) = mpsc::unbounded_channel();
+    LOG_QUEUE.get_or_init(|| log_sender);
+    tokio::spawn(log_task(log_receiver));
+
+    if CONFIG.startup_announcement {
+        announce_startup(&bot).await;
+    }
+
+    if let Some(backup_chat) = CONFIG.backup_chat {
+        tokio::spawn(backup_task(bot.clone(), ChatId(backup_chat)));
+    }
+
+    if let Some(interval_secs) = CONFIG.expiry_sweep_interval_secs {
+        tokio::spawn(expiry_sweep_task(bot.clone(), interval_secs));
+    }
+
+    if let Some(interval_secs) = CONFIG.reconcile_interval_secs {
+        tokio::spawn(reconcile_task(bot.clone(), interval_secs));
+    }
+
+    if let Some(max_distinct_ips) = CONFIG.max_distinct_ips {
+        tokio::spawn(ip_limit_task(bot.clone(), max_distinct_ips));
+    }
+
+    if let Some(max_auth_failures) = CONFIG.max_auth_failures {
+        tokio::spawn(auth_failure_task(bot.clone(), max_auth_failures));
+    }
+
+    tokio::spawn(precise_expiry_task(bot.clone()));
+    tokio::spawn(schedule_task(bot.clone()));
+    tokio::spawn(reminder_task(bot.clone()));
+    tokio::spawn(activation_task(bot.clone()));
+
+    if let Some(retention_days) = CONFIG.audit_retention_days {
+        tokio::spawn(audit_prune_task(retention_days));
+    }
+
+    let handler = dptree::entry()
+        .branch(
+            Update::filter_message()
+                .filter_command::<Command>()
+                .endpoint(answer),
+        )
+        .branch(Update::filter_callback_query().endpoint(callback_handler));
+
+    let admin_dispatcher = async {
+        Dispatcher::builder(bot, handler)
+            .error_handler(Arc::new(handle_request_error))
+            .enable_ctrlc_handler()
+            .build()
+            .dispatch()
+            .await
+    };
+
+    match &CONFIG.sales_bot_token {
+        Some(sales_bot_token) => {
+            log::info!("Starting sales bot...");
+
+            let sales_bot = Bot::new(sales_bot_token);
+            let sales_handler = Update::filter_message()
+                .filter_command::<SalesCommand>()
+                .endpoint(sales_answer);
+
+            let sales_dispatcher = async {
+                Dispatcher::builder(sales_bot, sales_handler)
+                    .error_handler(Arc::new(handle_request_error))
+                    .enable_ctrlc_handler()
+                    .build()
+                    .dispatch()
+                    .await
+            };
+
+            tokio::join!(admin_dispatcher, sales_dispatcher);
+        }
+        None => admin_dispatcher.await,
+    }
+}
+
+#[derive(BotCommands, Clone)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+enum Command {
+    #[command(description = "display this text.")]
+    Help,
+    #[command(description = "get user's expiry date")]
+    GetExp(String),
+    #[command(description = "list users expiring on a YYYY-MM-DD date")]
+    ExpiringOn(String),
+    #[command(description = "show user's details and connection IPs")]
+    Info(String),
+    #[command(description = "show when a user last logged in")]
+    Last(String),
+    #[command(
+        description = "get user's provisioning bundle as a zip",
+        parse_with = parse_password_args
+    )]
+    Bundle { username: String, password: String },
+    #[command(
+        description = "get user's printable credentials card as an image",
+        parse_with = parse_password_args
+    )]
+    Card { username: String, password: String },
+    #[command(description = "check that user's Sagernet link decodes back to its fields")]
+    VerifyLink(String),
+    #[command(
+        description = "generate a Sagernet link without a user: <server> <port> <user> <pass> <location> <exp>",
+        parse_with = "split"
+    )]
+    GenLink {
+        server_address: String,
+        port: u32,
+        username: String,
+        password: String,
+        location: String,
+        exp_date: String,
+    },
+    #[command(description = "compare user's expiry date with the one in its comment")]
+    Drift(String),
+    #[command(description = "compare user's expiry in /etc/shadow and chage -l, --fix repairs it")]
+    VerifyExpiry(String),
+    #[command(description = "show user's raw chage and passwd data")]
+    Dump(String),
+    #[command(description = "lock user")]
+    Lock(String),
+    #[command(description = "unlock user")]
+    Unlock(String),
+    #[command(description = "delete user, --force allows super-admins to delete unmanaged users")]
+    UserDel(String),
+    #[command(description = "move user to the quarantine group and lock it")]
+    Quarantine(String),
+    #[command(description = "release user from quarantine")]
+    Release(String),
+    #[command(description = "freeze user's remaining days and lock it")]
+    Freeze(String),
+    #[command(
+        description = "remind before user's expiry, e.g. 7,3,1 days, or off",
+        parse_with = "split"
+    )]
+    Reminders { username: String, offsets: String },
+    #[command(description = "unfreeze user, restoring its remaining days from today")]
+    Unfreeze(String),
+    #[command(
+        description = "cap user's throughput in Mbit/s, 0 removes the cap",
+        parse_with = "split"
+    )]
+    RateLimit { username: String, mbit: u32 },
+    #[command(description = "block or unblock a country: add <cc>, remove <cc> or list")]
+    Geoblock(String),
+    #[command(description = "check the sshd config and reload the SSH server")]
+    RestartSsh,
+    #[command(description = "change user's max logins", parse_with = "split")]
+    ChangeMax { username: String, group: String },
+    #[command(description = "change user's password", parse_with = parse_password_args)]
+    ChangePass { username: String, password: String },
+    #[command(description = "change user's login shell", parse_with = "split")]
+    Shell { username: String, shell: String },
+    #[command(description = "reset user's password and get a one-time link to it")]
+    SecurePass(String),
+    #[command(description = "change user's expiry date", parse_with = "split")]
+    ChangeExp { username: String, exp_date: String },
+    #[command(description = "renew user's expiry date", parse_with = "split")]
+    Renew { username: String, days: i64 },
+    #[command(
+        description = "add days to user's current expiry date instead of today",
+        parse_with = "split"
+    )]
+    Extend { username: String, days: i64 },
+    #[command(description = "renew several users: <days> <user1> <user2> ...")]
+    BulkRenew(String),
+    #[command(description = "renew user until a date", parse_with = "split")]
+    RenewUntil { username: String, exp_date: String },
+    #[command(description = "add new user manually", parse_with = parse_useradd_args)]
+    UserAdd {
+        username: String,
+        group: String,
+        exp_date: String,
+        password: String,
+    },
+    #[command(
+        description = "add new user manually with comma-separated supplementary groups",
+        parse_with = parse_useraddgroups_args
+    )]
+    UserAddGroups {
+        username: String,
+        group: String,
+        groups: String,
+        exp_date: String,
+        password: String,
+    },
+    #[command(
+        description = "add new user expiring at an RFC 3339 time, like 2024-01-31T18:00:00+00:00",
+        parse_with = "split"
+    )]
+    UserAddPrecise {
+        username: String,
+        group: String,
+        expires_at: String,
+    },
+    #[command(
+        description = "add new user whose days start counting at its first login",
+        parse_with = parse_useraddonlogin_args
+    )]
+    UserAddOnLogin {
+        username: String,
+        group: String,
+        days: i64,
+        password: String,
+    },
+    #[command(description = "add new user automatically", parse_with = "split")]
+    AutoAdd { group: String, days: i64 },
+    #[command(
+        description = "add new user automatically on a date: <activate_date> <group> <days>",
+        parse_with = "split"
+    )]
+    Schedule {
+        activate_date: String,
+        group: String,
+        days: i64,
+    },
+    #[command(description = "list scheduled users")]
+    Scheduled,
+    #[command(description = "cancel a scheduled user", parse_with = "split")]
+    CancelSchedule { id: u64 },
+    #[command(
+        description = "add new user with the group, expiry and shell of another",
+        parse_with = "split"
+    )]
+    Clone { source: String, username: String },
+    #[command(description = "change the username prefix of new users")]
+    SetPrefix(String),
+    #[command(
+        description = "delete users expired for at least the given days, --preview only lists them"
+    )]
+    Cleanup(String),
+    #[command(description = "list home directories whose user no longer exists")]
+    Orphans,
+    #[command(description = "compare system users with the users the bot tracks")]
+    Diff,
+    #[command(description = "transfer user to another admin", parse_with = "split")]
+    Transfer { username: String, admin: i64 },
+    #[command(description = "show the disk space and inodes used by managed users' homes")]
+    DiskUsage,
+    #[command(description = "show users with the most active sessions")]
+    Top(String),
+    #[command(description = "export users as a JSON Lines file")]
+    Export,
+    #[command(description = "get the current config with secrets redacted")]
+    Config,
+    #[command(description = "show recent Telegram flood limit hits and the current backoff")]
+    ApiStatus,
+    #[command(description = "show server load, memory and disk usage")]
+    Load,
+    #[command(description = "list the commands executed in the last minutes: [minutes] [page]")]
+    Recent(String),
+    #[command(description = "show the name of a Telegram chat id, like an admin's")]
+    Whois(String),
+    #[command(description = "check the log chat and admin setup")]
+    SelfTest,
+    #[command(description = "reload the admin list file")]
+    ReloadAdmins,
+}
+
+/// Posts the bot version, user count, privileges and config warnings to the log chat and pins it.
+async fn announce_startup(bot: &Bot) {
+    let prefix = CONFIG.active_prefix();
+    let is_root = users::get_effective_uid() == 0;
+    let warnings = CONFIG.warnings();
+
+    let mut announcement = format!(
+        "Bot started at {}, version {}\nUsers with prefix {prefix}: {}\nRunning as root: {}",
+        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC"),
+        env!("CARGO_PKG_VERSION"),
+        lib::count_users_with_prefix(&prefix),
+        if is_root {
+            "yes"
+        } else {
+            "no, managing users will fail"
+        }
+    );
+
+    if warnings.is_empty() {
+        announcement.push_str("\nConfig: OK");
+    } else {
+        announcement.push_str(&format!("\nConfig warnings:\n{}", warnings.join("\n")));
+    }
+
+    let log_chat = ChatId(CONFIG.log_chat);
+
+    match bot.send_message(log_chat, announcement).await {
+        Ok(sent) => {
+            if let Err(err) = bot
+                .pin_chat_message(log_chat, sent.id)
+                .disable_notification(true)
+                .await
+            {
+                record_rate_limit(&err);
+                log::warn!("Couldn't pin startup announcement: {err}");
+            }
+        }
+        Err(err) => {
+            record_rate_limit(&err);
+            log::error!("Couldn't send startup announcement: {err}");
+        }
+    }
+}
+
+/// Periodically reloads the admin user IDs in `admin_list_file`.
+async fn admin_list_reload_task() {
+    let secs = CONFIG.admin_list_reload_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = CONFIG.reload_admin_list_file() {
+            log::error!("Couldn't reload admin list file: {err}");
+        }
+    }
+}
+
+/// Periodically locks the users created by `/useraddprecise` whose expiry time has passed.
+async fn precise_expiry_task(bot: Bot) {
+    let secs = CONFIG.precise_expiry_check_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+
+    loop {
+        interval.tick().await;
+
+        for result in lib::lock_precise_expired_users() {
+            let report = match result {
+                Ok(user_status) => {
+                    format!("Locked user {} at its expiry time", user_status.username)
+                }
+                Err(err) => err,
+            };
+
+            log::info!("{report}");
+
+            log_text(&bot, report);
+        }
+    }
+}
+
+/// Periodically starts the days of the users created by `/useraddonlogin` that logged in.
+async fn activation_task(bot: Bot) {
+    let secs = CONFIG.activation_check_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+
+    loop {
+        interval.tick().await;
+
+        for result in lib::activate_logged_in_users() {
+            let report = match result {
+                Ok(user_exp) => format!(
+                    "Activated user {} at its first login, it expires on {}",
+                    user_exp.username,
+                    lib::display_date(&user_exp.exp_date)
+                ),
+                Err(err) => err,
+            };
+
+            log::info!("{report}");
+
+            log_text(&bot, report);
+        }
+    }
+}
+
+/// Creates the scheduled users whose activation date has come, sending their details to the admin
+/// that scheduled them.
+async fn schedule_task(bot: Bot) {
+    let secs = CONFIG.schedule_check_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+
+    loop {
+        interval.tick().await;
+
+        let due = match lib::schedule::due() {
+            Ok(due) => due,
+            Err(err) => {
+                log::error!("Couldn't check scheduled users: {err}");
+                continue;
+            }
+        };
+
+        for scheduled_user in due {
+            let admin = ChatId(scheduled_user.admin);
+            let result = create_owned_user(scheduled_user.admin, || {
+                lib::auto_newuser(
+                    &CONFIG.admin_prefix(scheduled_user.admin),
+                    &scheduled_user.group,
+                    scheduled_user.days,
+                )
+            });
+
+            let report = match result {
+                Ok(sshuser) => {
+                    if let Err(err) = lib::schedule::complete(scheduled_user.id) {
+                        log::error!("Couldn't remove schedule {}: {err}", scheduled_user.id);
+                    }
+
+                    if let Err(err) = send_user_details(&bot, admin, &sshuser).await {
+                        record_rate_limit(&err);
+                        log::error!("Couldn't send scheduled user {}: {err}", sshuser.username);
+                    }
+
+                    format!(
+                        "Created scheduled user {} (schedule {}) for admin {}",
+                        sshuser.username, scheduled_user.id, scheduled_user.admin
+                    )
+                }
+                Err(err) => {
+                    let report = format!(
+                        "Couldn't create scheduled user (schedule {}) for admin {}, retrying on the next check: {err}",
+                        scheduled_user.id, scheduled_user.admin
+                    );
+
+                    // Only the first failure is sent to the admin, the retries go to the log.
+                    match lib::schedule::record_failure(scheduled_user.id) {
+                        Ok(1) => {
+                            if let Err(err) = bot.send_message(admin, &report).await {
+                                record_rate_limit(&err);
+                                log::error!(
+                                    "Couldn't notify admin {}: {err}",
+                                    scheduled_user.admin
+                                );
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            log::error!(
+                                "Couldn't record the failure of schedule {}: {err}",
+                                scheduled_user.id
+                            );
+                        }
+                    }
+
+                    report
+                }
+            };
+
+            log::info!("{report}");
+
+            log_text(&bot, report);
+        }
+    }
+}
+
+/// Prunes the audit entries older than `audit_retention_days` every hour.
+async fn audit_prune_task(retention_days: u32) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let pruned = lib::audit::prune(retention_days);
+        log::info!("Pruned {pruned} audit entries older than {retention_days} days");
+    }
+}
+
+/// Periodically sends a JSON export of the managed users to the backup chat.
+async fn backup_task(bot: Bot, backup_chat: ChatId) {
+    let hours = CONFIG.backup_interval_hours.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(hours * 60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        let file_name = format!("users-{}.json", lib::today().format("%Y-%m-%d"));
+        let result = match lib::export_users_json(&CONFIG.active_prefix()) {
+            Ok(json) => bot
+                .send_document(
+                    backup_chat,
+                    InputFile::memory(json.into_bytes()).file_name(file_name),
+                )
+                .await
+                .map_err(|err| {
+                    record_rate_limit(&err);
+                    err.to_string()
+                }),
+            Err(err) => Err(err),
+        };
+
+        let report = match result {
+            Ok(_) => "Backup succeeded".to_string(),
+            Err(err) => {
+                log::error!("Backup failed: {err}");
+                format!("Backup failed: {err}")
+            }
+        };
+
+        log_text(&bot, report);
+    }
+}
+
+/// Periodically locks the expired users that aren't locked yet.
+///
+/// A random delay of up to `expiry_sweep_jitter_secs` is added to every interval, so several
+/// instances sharing storage don't sweep at the same moment.
+async fn expiry_sweep_task(bot: Bot, interval_secs: u64) {
+    loop {
+        let jitter_secs = rand::thread_rng().gen_range(0..=CONFIG.expiry_sweep_jitter_secs);
+        tokio::time::sleep(Duration::from_secs(interval_secs.max(1) + jitter_secs)).await;
+
+        let expired_users = lib::get_users_filtered(
+            &CONFIG.active_prefix(),
+            GroupFilter::Any,
+            ExpiryFilter::Expired,
+        );
+
+        for username in expired_users {
+            if lib::is_user_locked(&username) == Some(true) {
+                continue;
+            }
+
+            if lib::in_grace_period(&username) {
+                warn_grace_period(&bot, &username).await;
+                continue;
+            }
+
+            let report = match lib::lock_expired_user(&username) {
+                Ok(_) => format!("Locked expired user {username}"),
+                Err(err) => format!("Couldn't lock expired user {username}: {err}"),
+            };
+
+            log::info!("{report}");
+
+            log_text(&bot, report);
+        }
+    }
+}
+
+/// Periodically re-applies the expiry dates and quarantines recorded in the users' comment fields
+/// where the system has drifted from them, reporting every correction to the log chat.
+async fn reconcile_task(bot: Bot, interval_secs: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        for username in lib::get_users_core(&CONFIG.active_prefix(), None) {
+            for correction in lib::reconcile_user(&username) {
+                log::warn!("{correction}");
+                log_text(&bot, correction);
+            }
+        }
+    }
+}
+
+/// Periodically sends the expiry reminders set with `/reminders` that are due.
+async fn reminder_task(bot: Bot) {
+    let secs = CONFIG.reminder_check_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+
+    loop {
+        interval.tick().await;
+
+        for reminder in lib::take_due_reminders() {
+            match reminder {
+                Ok(reminder) => remind_expiry(&bot, &reminder).await,
+                Err(err) => log::error!("{err}"),
+            }
+        }
+    }
+}
+
+/// Sends a reminder that a user expires soon to its owner and the log chat.
+async fn remind_expiry(bot: &Bot, reminder: &lib::ExpiryReminder) {
+    let report = reminder.to_string();
+
+    log::info!("{report}");
+
+    if let Some(owner) = lib::get_user_owner(&reminder.username) {
+        if let Err(err) = bot.send_message(ChatId(owner), &report).await {
+            record_rate_limit(&err);
+            log::error!("Couldn't remind the owner of {}: {err}", reminder.username);
+        }
+    }
+
+    log_text(bot, report);
+}
+
+/// Warns the log chat and the owner of a user once that it's in its grace period.
+///
+/// The warned expiry date is recorded as `grace_warned=` in the user's comment field, so renewing
+/// the user re-arms the warning.
+async fn warn_grace_period(bot: &Bot, username: &str) {
+    let exp_date = match lib::get_chage_exp(username) {
+        Ok(user_exp) => user_exp.exp_date,
+        Err(err) => {
+            log::error!("Couldn't get expiry of {username}: {err}");
+            return;
+        }
+    };
+
+    if lib::get_comment_field(username, "grace_warned").as_deref() == Some(exp_date.as_str()) {
+        return;
+    }
+
+    let report = format!(
+        "User {username} expired on {} and is in its {} day grace period",
+        lib::display_date(&exp_date),
+        CONFIG.grace_period_days
+    );
+
+    log::info!("{report}");
+
+    if let Some(owner) = lib::get_user_owner(username) {
+        if let Err(err) = bot.send_message(ChatId(owner), &report).await {
+            record_rate_limit(&err);
+            log::error!("Couldn't warn the owner of {username}: {err}");
+        }
+    }
+
+    log_text(bot, report);
+
+    if let Err(err) = lib::set_comment_field(username, "grace_warned", &exp_date) {
+        log::error!("Couldn't record grace period warning of {username}: {err}");
+    }
+}
+
+/// Periodically kills the sessions of users connected from more than `max_distinct_ips` IPs.
+async fn ip_limit_task(bot: Bot, max_distinct_ips: u32) {
+    let secs = CONFIG.ip_check_interval_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+
+    loop {
+        interval.tick().await;
+
+        let connection_ips = match lib::get_all_connection_ips() {
+            Ok(connection_ips) => connection_ips,
+            Err(err) => {
+                log::error!("Couldn't check connection IPs: {err}");
+                continue;
+            }
+        };
+
+        let prefix = CONFIG.active_prefix();
+
+        for (username, ips) in connection_ips {
+            if !username.starts_with(&prefix) || ips.len() <= max_distinct_ips as usize {
+                continue;
+            }
+
+            let report = match lib::kill_user_sessions(&username) {
+                Ok(_) => format!(
+                    "Killed sessions of {username}, connected from {} IPs: {}",
+                    ips.len(),
+                    ips.join(", ")
+                ),
+                Err(err) => format!("Couldn't kill sessions of {username}: {err}"),
+            };
+
+            log::warn!("{report}");
+
+            log_text(&bot, report);
+        }
+    }
+}
+
+async fn auth_failure_task(bot: Bot, max_auth_failures: u32) {
+    let window = Duration::from_secs(CONFIG.failure_window_secs);
+    let mut watcher = lib::AuthLogWatcher::new();
+    let mut failures: HashMap<String, VecDeque<Instant>> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+
+    loop {
+        interval.tick().await;
+
+        let usernames = match watcher.read_failures() {
+            Ok(usernames) => usernames,
+            Err(err) => {
+                log::error!("Couldn't check failed logins: {err}");
+                continue;
+            }
+        };
+
+        let now = Instant::now();
+
+        for username in usernames {
+            if !lib::is_managed_user(&username) {
+                continue;
+            }
+
+            failures.entry(username).or_default().push_back(now);
+        }
+
+        for (username, times) in failures.iter_mut() {
+            while let Some(time) = times.front() {
+                if now.duration_since(*time) <= window {
+                    break;
+                }
+
+                times.pop_front();
+            }
+
+            if times.len() < max_auth_failures as usize {
+                continue;
+            }
+
+            let count = times.len();
+            times.clear();
+
+            if lib::is_user_locked(username) == Some(true) {
+                continue;
+            }
+
+            let report = match lib::lock_brute_forced_user(username) {
+                Ok(_) => format!(
+                    "Locked {username} after {count} failed logins within {} seconds",
+                    window.as_secs()
+                ),
+                Err(err) => format!("Couldn't lock {username} after {count} failed logins: {err}"),
+            };
+
+            log::warn!("{report}");
+
+            log_text(&bot, report);
+        }
+
+        failures.retain(|_, times| !times.is_empty());
+    }
+}
+
+/// Splits command arguments on any run of whitespace, keeping everything after the first `N - 1`
+/// arguments as the last one, so it can be a password with spaces in it.
+fn split_args<const N: usize>(input: &str) -> Result<[String; N], ParseError> {
+    let mut args: Vec<String> = Vec::with_capacity(N);
+    let mut rest = input.trim();
+
+    while args.len() < N - 1 && !rest.is_empty() {
+        let (arg, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        args.push(arg.to_string());
+        rest = tail.trim_start();
+    }
+
+    if !rest.is_empty() {
+        args.push(rest.to_string());
+    }
+
+    let found = args.len();
+    args.try_into().map_err(|_| ParseError::TooFewArguments {
+        expected: N,
+        found,
+        message: format!("Expected {N} arguments"),
+    })
+}
+
+fn parse_days(days: &str) -> Result<i64, ParseError> {
+    days.parse()
+        .map_err(|err| ParseError::IncorrectFormat(Box::new(err)))
+}
+
+fn parse_password_args(input: String) -> Result<(String, String), ParseError> {
+    let [username, password] = split_args(&input)?;
+
+    Ok((username, password))
 }
 
-#[tokio::main]
-async fn main() {
-    pretty_env_logger::init();
-    log::info!("Starting command bot...");
+fn parse_useradd_args(input: String) -> Result<(String, String, String, String), ParseError> {
+    let [username, group, exp_date, password] = split_args(&input)?;
+
+    Ok((username, group, exp_date, password))
+}
+
+fn parse_useraddgroups_args(
+    input: String,
+) -> Result<(String, String, String, String, String), ParseError> {
+    let [username, group, groups, exp_date, password] = split_args(&input)?;
+
+    Ok((username, group, groups, exp_date, password))
+}
+
+fn parse_useraddonlogin_args(input: String) -> Result<(String, String, i64, String), ParseError> {
+    let [username, group, days, password] = split_args(&input)?;
+
+    Ok((username, group, parse_days(&days)?, password))
+}
+
+/// Commands of the public-facing sales bot.
+#[derive(BotCommands, Clone)]
+#[command(
+    rename_rule = "lowercase",
+    description = "These commands are supported:"
+)]
+enum SalesCommand {
+    #[command(description = "display this text.")]
+    Help,
+    #[command(description = "add new user automatically", parse_with = "split")]
+    AutoAdd { group: String, days: i64 },
+}
+
+async fn sales_answer(bot: Bot, msg: Message, cmd: SalesCommand) -> ResponseResult<()> {
+    match cmd {
+        SalesCommand::Help => {
+            bot.send_message(msg.chat.id, SalesCommand::descriptions().to_string())
+                .await?;
+        }
+        SalesCommand::AutoAdd { group, days } => {
+            if !CONFIG.sales_allowlist.contains(&msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::validate_days(days, CONFIG.max_expiry_days).and_then(|_| {
+                create_owned_user(msg.chat.id.0, || {
+                    lib::auto_newuser(&CONFIG.admin_prefix(msg.chat.id.0), &group, days)
+                })
+            }) {
+                Ok(sshuser) => send_new_user(&bot, &msg, &sshuser).await?,
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+    };
+
+    Ok(())
+}
+
+async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
+    if CONFIG.is_admin(msg.chat.id.0) && needs_confirmation(&msg) {
+        let keyboard = InlineKeyboardMarkup::new(vec![vec![
+            InlineKeyboardButton::callback("Yes", "confirm"),
+            InlineKeyboardButton::callback("No", "cancel"),
+        ]]);
+
+        bot.send_message(msg.chat.id, "Are you sure?")
+            .reply_to_message_id(msg.id)
+            .reply_markup(keyboard)
+            .await?;
+
+        return Ok(());
+    }
+
+    if CONFIG.alert_unauthorized && !CONFIG.is_admin(msg.chat.id.0) && !matches!(cmd, Command::Help)
+    {
+        alert_unauthorized(&bot, &msg);
+    }
+
+    execute(bot, msg, cmd).await
+}
+
+/// How long Telegram flood limit hits are remembered for `/apistatus`.
+const RATE_LIMIT_HISTORY: Duration = Duration::from_secs(60 * 60);
+
+/// When Telegram answered with a flood limit in the last `RATE_LIMIT_HISTORY`, and how long it
+/// asked to wait.
+static RATE_LIMIT_HITS: OnceLock<Mutex<VecDeque<(Instant, Duration)>>> = OnceLock::new();
+
+fn rate_limit_hits() -> std::sync::MutexGuard<'static, VecDeque<(Instant, Duration)>> {
+    RATE_LIMIT_HITS
+        .get_or_init(|| Mutex::new(VecDeque::new()))
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// Remembers a flood limit hit if the error is one, dropping hits older than
+/// `RATE_LIMIT_HISTORY`.
+fn record_rate_limit(err: &teloxide::RequestError) {
+    let teloxide::RequestError::RetryAfter(retry_after) = err else {
+        return;
+    };
+
+    let now = Instant::now();
+    let mut hits = rate_limit_hits();
+
+    hits.push_back((now, retry_after.duration()));
+    hits.retain(|(hit_at, _)| now.duration_since(*hit_at) < RATE_LIMIT_HISTORY);
+}
+
+/// Handles the errors returned by command handlers, recording flood limit hits for
+/// `/apistatus`.
+async fn handle_request_error(err: teloxide::RequestError) {
+    record_rate_limit(&err);
+    log::error!("Error while handling an update: {err}");
+}
+
+/// Describes the flood limit hits of the last `RATE_LIMIT_HISTORY` and the remaining backoff.
+fn api_status() -> String {
+    let now = Instant::now();
+    let mut hits = rate_limit_hits();
+    hits.retain(|(hit_at, _)| now.duration_since(*hit_at) < RATE_LIMIT_HISTORY);
+
+    let last_5_minutes = hits
+        .iter()
+        .filter(|(hit_at, _)| now.duration_since(*hit_at) < Duration::from_secs(5 * 60))
+        .count();
+    let backoff = hits
+        .iter()
+        .filter_map(|(hit_at, retry_after)| (*hit_at + *retry_after).checked_duration_since(now))
+        .max()
+        .unwrap_or_default();
+    let last_hit = hits
+        .back()
+        .map_or("never".to_string(), |(hit_at, retry_after)| {
+            format!(
+                "{} seconds ago, asked to wait {} seconds",
+                now.duration_since(*hit_at).as_secs(),
+                retry_after.as_secs()
+            )
+        });
+
+    format!(
+        "flood limit hits in the last hour: `{}`\nflood limit hits in the last 5 minutes: `{}`\nlast hit: `{}`\ncurrent backoff: `{} seconds`\nlog interval: `{} ms`",
+        hits.len(),
+        last_5_minutes,
+        last_hit,
+        backoff.as_secs(),
+        CONFIG.log_interval_ms
+    )
+}
+
+/// Number of commands `/recent` lists per page.
+const RECENT_PAGE_SIZE: usize = 20;
+
+/// Names of the chats resolved by `/whois`, keyed by chat id.
+static CHAT_NAMES: OnceLock<Mutex<HashMap<i64, String>>> = OnceLock::new();
+
+/// Resolves a chat id to its name and username, or title for groups, with `getChat`. Resolved
+/// names are cached until the bot restarts.
+async fn resolve_chat_name(bot: &Bot, chat_id: i64) -> ResponseResult<String> {
+    let chat_names = CHAT_NAMES.get_or_init(|| Mutex::new(HashMap::new()));
+
+    let cached = chat_names
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .get(&chat_id)
+        .cloned();
+
+    if let Some(name) = cached {
+        return Ok(name);
+    }
+
+    let chat = bot.get_chat(ChatId(chat_id)).await?;
+
+    let mut name = match (chat.title(), chat.first_name(), chat.last_name()) {
+        (Some(title), _, _) => title.to_string(),
+        (None, Some(first_name), Some(last_name)) => format!("{first_name} {last_name}"),
+        (None, Some(first_name), None) => first_name.to_string(),
+        _ => "unnamed".to_string(),
+    };
+
+    if let Some(username) = chat.username() {
+        name.push_str(&format!(" (@{username})"));
+    }
+
+    chat_names
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(chat_id, name.clone());
+
+    Ok(name)
+}
+
+/// When each chat that isn't an admin last triggered an unauthorized command alert.
+static UNAUTHORIZED_ALERTS: OnceLock<Mutex<HashMap<i64, Instant>>> = OnceLock::new();
+
+/// Reports a command attempted by a non-admin to the log chat, at most once per
+/// `unauthorized_alert_interval_secs` for each chat.
+fn alert_unauthorized(bot: &Bot, msg: &Message) {
+    let now = Instant::now();
+    let interval = Duration::from_secs(CONFIG.unauthorized_alert_interval_secs);
+
+    {
+        let mut alerts = UNAUTHORIZED_ALERTS
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        alerts.retain(|_, alerted_at| now.duration_since(*alerted_at) < interval);
+
+        if alerts.contains_key(&msg.chat.id.0) {
+            return;
+        }
+
+        alerts.insert(msg.chat.id.0, now);
+    }
+
+    let username = msg
+        .from()
+        .and_then(|user| user.username.as_deref())
+        .map_or(String::new(), |username| format!(" (@{username})"));
+
+    log_text(
+        bot,
+        format!(
+            "Unauthorized command from chat {}{username}: {}",
+            msg.chat.id.0,
+            msg.text().unwrap_or_default()
+        ),
+    );
+}
+
+/// Returns the lowercase name of the command in the message, without the slash and bot username.
+fn command_name(msg: &Message) -> String {
+    msg.text()
+        .and_then(|text| text.split_whitespace().next())
+        .map(|command| command.trim_start_matches('/'))
+        .and_then(|command| command.split('@').next())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Returns whether the command in the message is listed in `confirm_commands`.
+fn needs_confirmation(msg: &Message) -> bool {
+    let command = command_name(msg);
+
+    // A bad sshd config could lock everyone out, so reloading it is always confirmed.
+    command == "restartssh" || CONFIG.confirm_commands.contains(&command)
+}
+
+/// A message waiting to be sent to the log chat, with the bot that sends it.
+///
+/// Forwarded messages can carry a note about their outcome, sent as a reply to the forward.
+enum LogEntry {
+    Forward(Bot, ChatId, MessageId, Option<String>),
+    Text(Bot, String),
+}
+
+static LOG_QUEUE: OnceLock<mpsc::UnboundedSender<LogEntry>> = OnceLock::new();
+
+/// Sends the queued log entries to the log chat, at most one every `log_interval_ms`.
+async fn log_task(mut receiver: mpsc::UnboundedReceiver<LogEntry>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(CONFIG.log_interval_ms.max(1)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    while let Some(entry) = receiver.recv().await {
+        interval.tick().await;
+
+        let result = match entry {
+            LogEntry::Forward(bot, chat_id, message_id, note) => {
+                match bot
+                    .forward_message(ChatId(CONFIG.log_chat), chat_id, message_id)
+                    .await
+                {
+                    Ok(forwarded) => match note {
+                        Some(note) => bot
+                            .send_message(ChatId(CONFIG.log_chat), note)
+                            .reply_to_message_id(forwarded.id)
+                            .await
+                            .map(|_| ()),
+                        None => Ok(()),
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            LogEntry::Text(bot, text) => bot
+                .send_message(ChatId(CONFIG.log_chat), text)
+                .await
+                .map(|_| ()),
+        };
+
+        if let Err(err) = result {
+            record_rate_limit(&err);
+            log::error!("Couldn't send to log chat: {err}");
+        }
+    }
+}
+
+/// Queues an entry for the log chat.
+fn queue_log(entry: LogEntry) {
+    match LOG_QUEUE.get() {
+        Some(sender) => {
+            if sender.send(entry).is_err() {
+                log::error!("Log chat queue is closed");
+            }
+        }
+        None => log::error!("Log chat queue isn't initialized"),
+    }
+}
+
+/// Queues a text message for the log chat.
+fn log_text(bot: &Bot, text: String) {
+    queue_log(LogEntry::Text(bot.clone(), text));
+}
+
+/// Gets the first argument of the command in a message, usually the affected username.
+fn command_target(msg: &Message) -> &str {
+    msg.text()
+        .and_then(|text| text.split_whitespace().nth(1))
+        .unwrap_or_default()
+}
+
+/// Records the command in the message in the audit log, and queues it for the log chat if it's
+/// listed in `log_commands`, with a note that it succeeded.
+fn log_command(bot: &Bot, msg: &Message) {
+    let target = command_target(msg);
+    lib::audit::record(msg.chat.id.0, &command_name(msg), target);
+
+    if CONFIG.log_commands.contains(&command_name(msg)) {
+        let note = format!("Succeeded for {}", target_label(target));
+        queue_log(LogEntry::Forward(
+            bot.clone(),
+            msg.chat.id,
+            msg.id,
+            Some(note),
+        ));
+    }
+}
+
+/// Queues a command that failed for the log chat if it's listed in `log_commands`, with a note
+/// giving the error. Failed commands aren't recorded in the audit log.
+fn log_failed_command(bot: &Bot, msg: &Message, err: &str) {
+    if CONFIG.log_commands.contains(&command_name(msg)) {
+        let note = format!("Failed for {}: {err}", target_label(command_target(msg)));
+        queue_log(LogEntry::Forward(
+            bot.clone(),
+            msg.chat.id,
+            msg.id,
+            Some(note),
+        ));
+    }
+}
+
+/// Describes the target of a command in log chat notes.
+fn target_label(target: &str) -> &str {
+    if target.is_empty() {
+        "no target"
+    } else {
+        target
+    }
+}
+
+/// Handles the inline keyboard buttons of confirmation prompts.
+async fn callback_handler(bot: Bot, q: CallbackQuery) -> ResponseResult<()> {
+    bot.answer_callback_query(q.id).await?;
+
+    let (Some(data), Some(prompt)) = (q.data, q.message) else {
+        return Ok(());
+    };
+
+    if !CONFIG.is_admin(prompt.chat.id.0) {
+        return Ok(());
+    }
+
+    match data.as_str() {
+        "confirm" => confirm_command(bot, prompt).await,
+        "delete_orphans" => delete_orphans(bot, prompt).await,
+        data if data.starts_with("cleanup:") => match data["cleanup:".len()..].parse::<i64>() {
+            Ok(days) => cleanup_users(bot, prompt, days).await,
+            Err(_) => Ok(()),
+        },
+        _ => {
+            bot.edit_message_text(prompt.chat.id, prompt.id, "Cancelled.")
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+/// Runs a command after the admin confirmed it.
+///
+/// The prompt is sent as a reply to the original command, so the command is parsed again from
+/// the replied message.
+async fn confirm_command(bot: Bot, prompt: Message) -> ResponseResult<()> {
+    let Some(original) = prompt.reply_to_message().cloned() else {
+        return Ok(());
+    };
+
+    bot.edit_message_text(prompt.chat.id, prompt.id, "Confirmed.")
+        .await?;
+
+    let me = bot.get_me().await?;
+    let Some(cmd) = original
+        .text()
+        .and_then(|text| Command::parse(text, me.username()).ok())
+    else {
+        return Ok(());
+    };
+
+    execute(bot, original, cmd).await
+}
+
+/// Deletes the orphaned home directories after the admin confirmed it, then logs the result.
+async fn delete_orphans(bot: Bot, prompt: Message) -> ResponseResult<()> {
+    let result = match lib::remove_orphan_homes(&CONFIG.home_base) {
+        Ok(removed) => format!(
+            "Removed {} orphaned home directories:\n{}",
+            removed.len(),
+            removed
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+        ),
+        Err(err) => err,
+    };
+
+    bot.edit_message_text(prompt.chat.id, prompt.id, result)
+        .await?;
+
+    queue_log(LogEntry::Forward(
+        bot.clone(),
+        prompt.chat.id,
+        prompt.id,
+        None,
+    ));
+
+    Ok(())
+}
+
+/// Lists the users `/cleanup` would delete, with their expiry dates and last logins.
+fn cleanup_preview(expired_users: &[lib::UserExp]) -> String {
+    let lines: Vec<String> = expired_users
+        .iter()
+        .map(|user_exp| {
+            let last_login = match lib::last_login(&user_exp.username) {
+                Ok(Some(last_login)) => last_login.summary(),
+                Ok(None) => "never".to_string(),
+                Err(_) => "unavailable".to_string(),
+            };
+
+            format!(
+                "{}: expired {}, last login {last_login}",
+                user_exp.username,
+                lib::display_date(&user_exp.exp_date)
+            )
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+/// Longest message `send_long_message` sends, leaving a margin under Telegram's 4096 limit, which
+/// counts UTF-16 code units rather than characters.
+const MAX_MESSAGE_CHARS: usize = 4000;
+
+/// Sends text as several messages when it's over Telegram's length limit, splitting it at line
+/// boundaries. Lines that are too long on their own are split wherever they reach the limit.
+async fn send_long_message(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    parse_mode: Option<ParseMode>,
+) -> ResponseResult<()> {
+    for chunk in split_message(text) {
+        let request = bot.send_message(chat_id, chunk);
+        match parse_mode {
+            Some(parse_mode) => request.parse_mode(parse_mode).await?,
+            None => request.await?,
+        };
+    }
+
+    Ok(())
+}
+
+/// Sends text like `send_long_message`, with the keyboard attached to the last message so it's
+/// shown below the whole list.
+async fn send_long_message_with_keyboard(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: &str,
+    keyboard: InlineKeyboardMarkup,
+) -> ResponseResult<()> {
+    let mut chunks = split_message(text);
+    let last = chunks.pop().unwrap_or_default();
+
+    for chunk in chunks {
+        bot.send_message(chat_id, chunk).await?;
+    }
+
+    bot.send_message(chat_id, last)
+        .reply_markup(keyboard)
+        .await?;
+
+    Ok(())
+}
+
+/// Splits text into chunks of at most `MAX_MESSAGE_CHARS` characters, see `send_long_message`.
+fn split_message(text: &str) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+    let mut chunk = String::new();
+    let mut chunk_chars = 0;
+
+    for line in text.lines() {
+        let line_chars = line.chars().count();
+
+        if chunk_chars > 0 && chunk_chars + 1 + line_chars > MAX_MESSAGE_CHARS {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_chars = 0;
+        }
+
+        if line_chars > MAX_MESSAGE_CHARS {
+            let chars: Vec<char> = line.chars().collect();
+            for part in chars.chunks(MAX_MESSAGE_CHARS) {
+                chunks.push(part.iter().collect());
+            }
+            continue;
+        }
+
+        if chunk_chars > 0 {
+            chunk.push('\n');
+            chunk_chars += 1;
+        }
+        chunk.push_str(line);
+        chunk_chars += line_chars;
+    }
+
+    if chunk_chars > 0 {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+/// Deletes the users expired at least `days` days ago after the admin confirmed it, then logs the
+/// result.
+///
+/// The users are looked up again, so users renewed since the preview are kept.
+async fn cleanup_users(bot: Bot, prompt: Message, days: i64) -> ResponseResult<()> {
+    let mut deleted: Vec<String> = Vec::new();
+    let mut failed: Vec<String> = Vec::new();
+
+    for user_exp in lib::expired_before(&CONFIG.admin_prefix(prompt.chat.id.0), days) {
+        match lib::userdel(&user_exp.username, false) {
+            Ok(_) => deleted.push(user_exp.username),
+            Err(err) => failed.push(format!("{}: {err}", user_exp.username)),
+        }
+    }
+
+    let mut result = format!("Deleted {} users:\n{}", deleted.len(), deleted.join("\n"));
+    if !failed.is_empty() {
+        result.push_str(&format!("\n\nFailed to delete:\n{}", failed.join("\n")));
+    }
+
+    bot.edit_message_text(prompt.chat.id, prompt.id, result)
+        .await?;
+
+    queue_log(LogEntry::Forward(
+        bot.clone(),
+        prompt.chat.id,
+        prompt.id,
+        None,
+    ));
+
+    Ok(())
+}
+
+async fn execute(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
+    match cmd {
+        Command::Help => {
+            send_long_message(
+                &bot,
+                msg.chat.id,
+                &Command::descriptions().to_string(),
+                None,
+            )
+            .await?;
+        }
+        Command::GetExp(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::get_chage_exp(&username) {
+                Ok(user_exp) => {
+                    bot.send_message(msg.chat.id, format!("{user_exp}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::ExpiringOn(date) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let Ok(date) = chrono::NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d") else {
+                bot.send_message(msg.chat.id, "Usage: /expiringon <YYYY-MM-DD>")
+                    .await?;
+                return Ok(());
+            };
+
+            let mut usernames: Vec<String> =
+                lib::get_all_expiries(&CONFIG.admin_prefix(msg.chat.id.0))
+                    .into_iter()
+                    .filter(|(_, expiry_date)| *expiry_date == date)
+                    .map(|(username, _)| username)
+                    .collect();
+            usernames.sort();
+
+            let date = lib::display_date(&date.format("%Y-%m-%d").to_string());
+            let text = if usernames.is_empty() {
+                format!("No users expire on {date}")
+            } else {
+                format!(
+                    "{} users expire on {date}:\n{}",
+                    usernames.len(),
+                    usernames.join("\n")
+                )
+            };
+
+            send_long_message(&bot, msg.chat.id, &text, None).await?;
+        }
+        Command::Info(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::get_user_info(&username) {
+                Ok(user_info) => {
+                    bot.send_message(msg.chat.id, format!("{user_info}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Last(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::last_login(&username) {
+                Ok(Some(last_login)) => {
+                    bot.send_message(msg.chat.id, format!("{last_login}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+                }
+                Ok(None) => {
+                    bot.send_message(msg.chat.id, format!("{username} never logged in"))
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Bundle { username, password } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let bundle_username = username.clone();
+            match render_image(move || lib::generate_bundle(&bundle_username, &password)).await {
+                Ok(bundle) => {
+                    bot.send_document(
+                        msg.chat.id,
+                        InputFile::memory(bundle).file_name(format!("{username}.zip")),
+                    )
+                    .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Card { username, password } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let card_username = username.clone();
+            match render_image(move || lib::generate_card(&card_username, &password)).await {
+                Ok(card) => {
+                    bot.send_document(
+                        msg.chat.id,
+                        InputFile::memory(card).file_name(format!("{username}.png")),
+                    )
+                    .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::VerifyLink(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::verify_sagernet_link(&username) {
+                Ok(link_fields) => {
+                    bot.send_message(msg.chat.id, format!("Link OK\n{link_fields}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::GenLink {
+            server_address,
+            port,
+            username,
+            password,
+            location,
+            exp_date,
+        } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            if !lib::is_super_admin(msg.chat.id.0) {
+                bot.send_message(msg.chat.id, "Only super-admins can generate links")
+                    .await?;
+                return Ok(());
+            }
+
+            match lib::sagernet_link_generator(
+                &server_address,
+                port,
+                &username,
+                &password,
+                &location,
+                &exp_date,
+            ) {
+                Ok(sagernet_link) => match render_qr_code(&sagernet_link).await {
+                    Ok(qr_bytes) => {
+                        bot.send_photo(msg.chat.id, InputFile::memory(qr_bytes))
+                            .caption(format!("`{sagernet_link}`"))
+                            .parse_mode(ParseMode::Markdown)
+                            .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(msg.chat.id, format!("{err}\n{sagernet_link}"))
+                            .await?;
+                    }
+                },
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Drift(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::detect_expiry_drift(&username) {
+                Ok(Some(expiry_drift)) => {
+                    bot.send_message(msg.chat.id, format!("{expiry_drift}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+                }
+                Ok(None) => {
+                    bot.send_message(msg.chat.id, "No expiry drift").await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::VerifyExpiry(args) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let (username, fix) = match args.split_whitespace().collect::<Vec<&str>>()[..] {
+                [username] => (username.to_string(), false),
+                [username, "--fix"] => (username.to_string(), true),
+                _ => {
+                    bot.send_message(msg.chat.id, "Usage: /verifyexpiry <username> [--fix]")
+                        .await?;
+                    return Ok(());
+                }
+            };
+
+            match lib::verify_expiry(&username, fix) {
+                Ok(expiry_check) => {
+                    bot.send_message(msg.chat.id, format!("{expiry_check}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    if expiry_check.fixed {
+                        log_command(&bot, &msg);
+                    }
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Dump(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::dump_user(&username) {
+                Ok(user_dump) => {
+                    bot.send_message(msg.chat.id, format!("{user_dump}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Lock(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::lock_user(&username) {
+                Ok(user_status) => {
+                    bot.send_message(msg.chat.id, format!("{user_status}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Unlock(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::unlock_user(&username) {
+                Ok(user_status) => {
+                    bot.send_message(msg.chat.id, format!("{user_status}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::UserDel(args) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let mut args = args.split_whitespace();
+            let username = args.next().unwrap_or_default();
+            let force = args.next() == Some("--force");
+
+            if force && !lib::is_super_admin(msg.chat.id.0) {
+                bot.send_message(msg.chat.id, "Only super-admins can use --force")
+                    .await?;
+                return Ok(());
+            }
+
+            match lib::userdel(username, force) {
+                Ok(user_status) => {
+                    bot.send_message(msg.chat.id, format!("{user_status}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Quarantine(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::quarantine_user(&username) {
+                Ok(user_status) => {
+                    bot.send_message(msg.chat.id, format!("{user_status}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Release(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::release_user(&username) {
+                Ok(user_status) => {
+                    bot.send_message(msg.chat.id, format!("{user_status}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Reminders { username, offsets } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::set_reminders(&username, &offsets) {
+                Ok(offsets) => {
+                    let reply = if offsets.is_empty() {
+                        format!("Removed reminders of {username}")
+                    } else {
+                        let offsets: Vec<String> =
+                            offsets.iter().map(|offset| offset.to_string()).collect();
+                        format!(
+                            "Reminding {username} {} days before its expiry date",
+                            offsets.join(", ")
+                        )
+                    };
+
+                    bot.send_message(msg.chat.id, reply).await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Freeze(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::freeze_user(&username) {
+                Ok(user_status) => {
+                    bot.send_message(msg.chat.id, format!("{user_status}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Unfreeze(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::unfreeze_user(&username) {
+                Ok(user_exp) => {
+                    bot.send_message(msg.chat.id, format!("{user_exp}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::RateLimit { username, mbit } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::set_rate_limit(&username, mbit) {
+                Ok(user_status) => {
+                    bot.send_message(msg.chat.id, format!("{user_status}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Geoblock(args) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            if !lib::is_super_admin(msg.chat.id.0) {
+                bot.send_message(msg.chat.id, "Only super-admins can change the geoblock")
+                    .await?;
+                return Ok(());
+            }
+
+            let args: Vec<&str> = args.split_whitespace().collect();
+            let result = match args.as_slice() {
+                ["add", code] => lib::geoblock::block_country(code)
+                    .map(|country| format!("Blocked traffic from {country}")),
+                ["remove", code] => lib::geoblock::unblock_country(code)
+                    .map(|country| format!("Unblocked traffic from {country}")),
+                ["list"] => {
+                    let countries = lib::geoblock::blocked_countries();
+                    bot.send_message(
+                        msg.chat.id,
+                        if countries.is_empty() {
+                            "No countries are blocked".to_string()
+                        } else {
+                            format!("Blocked countries: {}", countries.join(", "))
+                        },
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                _ => Err(
+                    "Usage: /geoblock add <cc>, /geoblock remove <cc> or /geoblock list"
+                        .to_string(),
+                ),
+            };
+
+            match result {
+                Ok(report) => {
+                    bot.send_message(msg.chat.id, report).await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::RestartSsh => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            if !lib::is_super_admin(msg.chat.id.0) {
+                bot.send_message(msg.chat.id, "Only super-admins can reload SSH")
+                    .await?;
+                return Ok(());
+            }
+
+            match lib::reload_ssh() {
+                Ok(report) => {
+                    bot.send_message(msg.chat.id, report)
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::ChangeMax { username, group } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::change_max(&username, &group) {
+                Ok(user_max) => {
+                    bot.send_message(msg.chat.id, format!("{user_max}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::ChangePass { username, password } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::change_pass(&username, &password) {
+                Ok(user_pass) => {
+                    bot.send_message(msg.chat.id, format!("{user_pass}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Shell { username, shell } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::change_shell(&username, &shell) {
+                Ok(user_shell) => {
+                    bot.send_message(msg.chat.id, format!("{user_shell}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    if let Some(warning) = lib::shell_tunnel_warning(&user_shell.shell) {
+                        bot.send_message(msg.chat.id, format!("Warning: {warning}"))
+                            .await?;
+                    }
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::SecurePass(username) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let Some(api_public_url) = CONFIG
+                .api_public_url
+                .as_ref()
+                .filter(|_| CONFIG.api_enabled)
+            else {
+                bot.send_message(
+                    msg.chat.id,
+                    "One-time links need api_enabled and api_public_url",
+                )
+                .await?;
+                return Ok(());
+            };
+
+            match lib::change_pass(&username, &lib::new_password()) {
+                Ok(user_pass) => {
+                    let token = lib::api::create_secret(
+                        format!(
+                            "username: {}\npassword: {}\n",
+                            user_pass.username, user_pass.password
+                        ),
+                        Duration::from_secs(CONFIG.secret_ttl_secs),
+                    );
+                    let link = format!("{}/secret/{token}", api_public_url.trim_end_matches('/'));
+
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Password of {username} was reset, it can be viewed once at:\n{link}"
+                        ),
+                    )
+                    .disable_web_page_preview(true)
+                    .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::ChangeExp { username, exp_date } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::change_exp(&username, &exp_date) {
+                Ok(user_exp) => {
+                    bot.send_message(msg.chat.id, format!("{user_exp}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Renew { username, days } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::validate_days(days, CONFIG.max_expiry_days)
+                .and_then(|_| lib::renew_user(&username, days))
+            {
+                Ok(user_exp) => {
+                    bot.send_message(msg.chat.id, format!("{user_exp}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Extend { username, days } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::validate_days(days, CONFIG.max_expiry_days)
+                .and_then(|_| lib::extend_user(&username, days))
+            {
+                Ok(user_exp) => {
+                    bot.send_message(msg.chat.id, format!("{user_exp}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::BulkRenew(args) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let mut args = args.split_whitespace();
+            let days = args.next().and_then(|days| days.parse::<i64>().ok());
+            let usernames: Vec<&str> = args.collect();
+
+            let Some(days) = days.filter(|_| !usernames.is_empty()) else {
+                bot.send_message(msg.chat.id, "Usage: /bulkrenew <days> <user1> <user2> ...")
+                    .await?;
+                return Ok(());
+            };
+
+            if let Err(err) = lib::validate_days(days, CONFIG.max_expiry_days) {
+                bot.send_message(msg.chat.id, err).await?;
+                return Ok(());
+            }
+
+            let (known, unknown): (Vec<&str>, Vec<&str>) = usernames
+                .into_iter()
+                .partition(|username| lib::user_exists(username));
+
+            let mut renewed: Vec<String> = Vec::new();
+            let mut failed: Vec<String> = Vec::new();
+
+            for username in known {
+                match lib::renew_user(username, days) {
+                    Ok(user_exp) => renewed.push(format!(
+                        "{username}: {}",
+                        lib::display_date(&user_exp.exp_date)
+                    )),
+                    Err(err) => failed.push(format!("{username}: {err}")),
+                }
+            }
+
+            let mut result = format!("Renewed {} users:\n{}", renewed.len(), renewed.join("\n"));
+            if !failed.is_empty() {
+                result.push_str(&format!("\n\nFailed to renew:\n{}", failed.join("\n")));
+            }
+            if !unknown.is_empty() {
+                result.push_str(&format!("\n\nUnknown users:\n{}", unknown.join("\n")));
+            }
+
+            send_long_message(&bot, msg.chat.id, &result, None).await?;
+
+            if !renewed.is_empty() {
+                log_command(&bot, &msg);
+            }
+        }
+        Command::RenewUntil { username, exp_date } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::change_exp(&username, &exp_date) {
+                Ok(user_exp) => {
+                    bot.send_message(msg.chat.id, format!("{user_exp}"))
+                        .parse_mode(ParseMode::Markdown)
+                        .await?;
+
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::UserAdd {
+            username,
+            group,
+            exp_date,
+            password,
+        } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            echo_parsed(
+                &bot,
+                &msg,
+                &[
+                    ("user", &username),
+                    ("group", &group),
+                    ("expiry", &exp_date),
+                ],
+            )
+            .await?;
+
+            match create_owned_user(msg.chat.id.0, || {
+                lib::create_or_retry(&username, |username| {
+                    lib::newuser(username, &group, &password, &exp_date, &[])
+                })
+            }) {
+                Ok(sshuser) => {
+                    report_username_variant(&bot, &msg, &username, &sshuser).await?;
+                    send_new_user(&bot, &msg, &sshuser).await?
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::UserAddGroups {
+            username,
+            group,
+            groups,
+            exp_date,
+            password,
+        } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            echo_parsed(
+                &bot,
+                &msg,
+                &[
+                    ("user", &username),
+                    ("group", &group),
+                    ("groups", &groups),
+                    ("expiry", &exp_date),
+                ],
+            )
+            .await?;
+
+            let supplementary_groups = lib::parse_group_list(&groups);
+            match create_owned_user(msg.chat.id.0, || {
+                lib::create_or_retry(&username, |username| {
+                    lib::newuser(
+                        username,
+                        &group,
+                        &password,
+                        &exp_date,
+                        &supplementary_groups,
+                    )
+                })
+            }) {
+                Ok(sshuser) => {
+                    report_username_variant(&bot, &msg, &username, &sshuser).await?;
+                    send_new_user(&bot, &msg, &sshuser).await?
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::UserAddPrecise {
+            username,
+            group,
+            expires_at,
+        } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            echo_parsed(
+                &bot,
+                &msg,
+                &[
+                    ("user", &username),
+                    ("group", &group),
+                    ("expiry", &expires_at),
+                ],
+            )
+            .await?;
+
+            match create_owned_user(msg.chat.id.0, || {
+                lib::newuser_precise(&username, &group, &expires_at)
+            }) {
+                Ok(sshuser) => send_new_user(&bot, &msg, &sshuser).await?,
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::UserAddOnLogin {
+            username,
+            group,
+            days,
+            password,
+        } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            echo_parsed(
+                &bot,
+                &msg,
+                &[
+                    ("user", &username),
+                    ("group", &group),
+                    ("days", &days.to_string()),
+                ],
+            )
+            .await?;
+
+            match lib::validate_days(days, CONFIG.max_expiry_days).and_then(|_| {
+                create_owned_user(msg.chat.id.0, || {
+                    lib::newuser_on_login(&username, &group, &password, days)
+                })
+            }) {
+                Ok(sshuser) => send_new_user(&bot, &msg, &sshuser).await?,
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::AutoAdd { group, days } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match lib::validate_days(days, CONFIG.max_expiry_days).and_then(|_| {
+                create_owned_user(msg.chat.id.0, || {
+                    lib::auto_newuser(&CONFIG.admin_prefix(msg.chat.id.0), &group, days)
+                })
+            }) {
+                Ok(sshuser) => send_new_user(&bot, &msg, &sshuser).await?,
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::Clone { source, username } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            match create_owned_user(msg.chat.id.0, || lib::clone_user(&source, &username)) {
+                Ok(sshuser) => send_new_user(&bot, &msg, &sshuser).await?,
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::DiskUsage => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let mut usages: Vec<lib::HomeUsage> = Vec::new();
+            let mut failed: Vec<String> = Vec::new();
 
-    let bot = Bot::new(&CONFIG.bot_token);
-    Command::repl(bot, answer).await;
-}
+            for username in lib::get_users_core(&CONFIG.admin_prefix(msg.chat.id.0), None) {
+                match lib::home_disk_usage(&username) {
+                    Ok(home_usage) => usages.push(home_usage),
+                    Err(err) => failed.push(format!("{username}: {err}")),
+                }
+            }
 
-#[derive(BotCommands, Clone)]
-#[command(
-    rename_rule = "lowercase",
-    description = "These commands are supported:"
-)]
-enum Command {
-    #[command(description = "display this text.")]
-    Help,
-    #[command(description = "get user's expiry date")]
-    GetExp(String),
-    #[command(description = "lock user")]
-    Lock(String),
-    #[command(description = "unlock user")]
-    Unlock(String),
-    #[command(description = "delete user")]
-    UserDel(String),
-    #[command(description = "change user's max logins", parse_with = "split")]
-    ChangeMax { username: String, group: String },
-    #[command(description = "change user's password", parse_with = "split")]
-    ChangePass { username: String, password: String },
-    #[command(description = "change user's expiry date", parse_with = "split")]
-    ChangeExp { username: String, exp_date: String },
-    #[command(description = "renew user's expiry date", parse_with = "split")]
-    Renew { username: String, days: i64 },
-    #[command(description = "add new user manually", parse_with = "split")]
-    UserAdd {
-        username: String,
-        group: String,
-        exp_date: String,
-        password: String,
-    },
-    #[command(description = "add new user automatically", parse_with = "split")]
-    AutoAdd { group: String, days: i64 },
-}
+            usages.sort_by(|a, b| b.disk_kb.cmp(&a.disk_kb));
 
-async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
-    match cmd {
-        Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                .await?
+            let mut report = format!(
+                "Total: {} MB, {} inodes in {} homes",
+                usages.iter().map(|usage| usage.disk_kb).sum::<u64>() / 1024,
+                usages.iter().map(|usage| usage.inodes).sum::<u64>(),
+                usages.len()
+            );
+            for home_usage in &usages {
+                report.push_str(&format!("\n{home_usage}"));
+            }
+            if !failed.is_empty() {
+                report.push_str(&format!("\n\nCouldn't measure:\n{}", failed.join("\n")));
+            }
+
+            send_long_message(&bot, msg.chat.id, &report, Some(ParseMode::Markdown)).await?;
         }
-        Command::GetExp(username) => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Top(count) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::get_chage_exp(&username) {
-                Ok(user_exp) => {
-                    bot.send_message(msg.chat.id, format!("{user_exp}"))
-                        .parse_mode(ParseMode::Markdown)
-                        .await?;
+            let count = if count.trim().is_empty() {
+                Ok(10)
+            } else {
+                count
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| "Invalid count".to_string())
+            };
+
+            match count.and_then(|count| {
+                lib::top_users_by_sessions(&CONFIG.admin_prefix(msg.chat.id.0), count)
+            }) {
+                Ok(top_users) if top_users.is_empty() => {
+                    bot.send_message(msg.chat.id, "No active sessions").await?;
+                }
+                Ok(top_users) => {
+                    let leaderboard = top_users
+                        .iter()
+                        .enumerate()
+                        .map(|(index, user)| {
+                            let rank = match index {
+                                0 => "🥇".to_string(),
+                                1 => "🥈".to_string(),
+                                2 => "🥉".to_string(),
+                                _ => format!("{}.", index + 1),
+                            };
+                            format!("{rank} `{}`: {} sessions", user.username, user.sessions)
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n");
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+                    send_long_message(&bot, msg.chat.id, &leaderboard, Some(ParseMode::Markdown))
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
-        Command::Lock(username) => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Schedule {
+            activate_date,
+            group,
+            days,
+        } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::lock_user(&username) {
-                Ok(user_status) => {
-                    bot.send_message(msg.chat.id, format!("{user_status}"))
+            match lib::validate_days(days, CONFIG.max_expiry_days).and_then(|_| {
+                lib::schedule::schedule_user(msg.chat.id.0, &activate_date, &group, days)
+            }) {
+                Ok(scheduled_user) => {
+                    bot.send_message(msg.chat.id, format!("{scheduled_user}"))
                         .parse_mode(ParseMode::Markdown)
                         .await?;
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
-        Command::Unlock(username) => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Scheduled => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::unlock_user(&username) {
-                Ok(user_status) => {
-                    bot.send_message(msg.chat.id, format!("{user_status}"))
-                        .parse_mode(ParseMode::Markdown)
-                        .await?;
-
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+            let scheduled_users: Vec<String> = match lib::schedule::scheduled_users() {
+                Ok(scheduled_users) => scheduled_users
+                    .iter()
+                    .filter(|s| s.admin == msg.chat.id.0 || lib::is_super_admin(msg.chat.id.0))
+                    .map(|s| s.to_string())
+                    .collect(),
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                    return Ok(());
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
+            };
+
+            if scheduled_users.is_empty() {
+                bot.send_message(msg.chat.id, "No scheduled users").await?;
+            } else {
+                send_long_message(
+                    &bot,
+                    msg.chat.id,
+                    &scheduled_users.join("\n\n"),
+                    Some(ParseMode::Markdown),
+                )
+                .await?;
             }
         }
-        Command::UserDel(username) => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::CancelSchedule { id } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::userdel(&username) {
-                Ok(user_status) => {
-                    bot.send_message(msg.chat.id, format!("{user_status}"))
-                        .parse_mode(ParseMode::Markdown)
-                        .await?;
+            match lib::schedule::cancel_schedule(id, msg.chat.id.0) {
+                Ok(scheduled_user) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!("Cancelled schedule:\n{scheduled_user}"),
+                    )
+                    .parse_mode(ParseMode::Markdown)
+                    .await?;
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
-        Command::ChangeMax { username, group } => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Transfer { username, admin } => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::change_max(&username, &group) {
-                Ok(user_max) => {
-                    bot.send_message(msg.chat.id, format!("{user_max}"))
+            match lib::transfer_user(&username, msg.chat.id.0, admin) {
+                Ok(user_owner) => {
+                    bot.send_message(msg.chat.id, format!("{user_owner}"))
                         .parse_mode(ParseMode::Markdown)
                         .await?;
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
-        Command::ChangePass { username, password } => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::SetPrefix(prefix) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::change_pass(&username, &password) {
-                Ok(user_pass) => {
-                    bot.send_message(msg.chat.id, format!("{user_pass}"))
-                        .parse_mode(ParseMode::Markdown)
-                        .await?;
+            if !lib::is_super_admin(msg.chat.id.0) {
+                bot.send_message(msg.chat.id, "Only super-admins can change the prefix")
+                    .await?;
+                return Ok(());
+            }
+
+            let prefix = prefix.trim();
+            let valid = prefix.starts_with(|c: char| c.is_ascii_lowercase())
+                && prefix
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+
+            if !valid {
+                bot.send_message(
+                    msg.chat.id,
+                    "Prefix must start with a lowercase letter and only contain lowercase letters, digits, _ and -",
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let old_prefix = CONFIG.active_prefix();
+
+            match CONFIG.set_prefix(prefix) {
+                Ok(_) => {
+                    bot.send_message(
+                        msg.chat.id,
+                        format!(
+                            "Prefix changed from {old_prefix} to {prefix}. Users under {old_prefix} are no longer counted when numbering new users."
+                        ),
+                    )
+                    .await?;
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+                    log_command(&bot, &msg);
+                }
+                Err(err) => {
+                    log_failed_command(&bot, &msg, &err);
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
-        Command::ChangeExp { username, exp_date } => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Cleanup(args) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::change_exp(&username, &exp_date) {
-                Ok(user_exp) => {
-                    bot.send_message(msg.chat.id, format!("{user_exp}"))
-                        .parse_mode(ParseMode::Markdown)
-                        .await?;
+            let args: Vec<&str> = args.split_whitespace().collect();
+            let preview_only = args.first() == Some(&"--preview");
+            let days = match args.as_slice() {
+                ["--preview", days] | [days] => days.parse::<i64>().ok(),
+                _ => None,
+            };
+
+            let Some(days) = days.filter(|days| *days >= 0) else {
+                bot.send_message(msg.chat.id, "Usage: /cleanup [--preview] <grace_days>")
+                    .await?;
+                return Ok(());
+            };
+
+            let expired_users = lib::expired_before(&CONFIG.admin_prefix(msg.chat.id.0), days);
+
+            if expired_users.is_empty() {
+                bot.send_message(
+                    msg.chat.id,
+                    format!("No users expired at least {days} days ago"),
+                )
+                .await?;
+                return Ok(());
+            }
+
+            let preview = format!(
+                "Users expired at least {days} days ago:\n{}",
+                cleanup_preview(&expired_users)
+            );
+
+            if preview_only {
+                send_long_message(&bot, msg.chat.id, &preview, None).await?;
+                return Ok(());
+            }
+
+            let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::callback("Delete all", format!("cleanup:{days}")),
+                InlineKeyboardButton::callback("Cancel", "cancel"),
+            ]]);
+
+            send_long_message_with_keyboard(&bot, msg.chat.id, &preview, keyboard).await?;
+        }
+        Command::Diff => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+            match lib::diff_users(&CONFIG.admin_prefix(msg.chat.id.0)) {
+                Ok(diff) if diff.is_empty() => {
+                    bot.send_message(msg.chat.id, "System users match the tracked users")
+                        .await?;
+                }
+                Ok(diff) => {
+                    send_long_message(&bot, msg.chat.id, &diff.to_string(), None).await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
-        Command::Renew { username, days } => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Orphans => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            match lib::renew_user(&username, days) {
-                Ok(user_exp) => {
-                    bot.send_message(msg.chat.id, format!("{user_exp}"))
-                        .parse_mode(ParseMode::Markdown)
+            match lib::find_orphan_homes(&CONFIG.home_base) {
+                Ok(orphans) if orphans.is_empty() => {
+                    bot.send_message(msg.chat.id, "No orphaned home directories")
                         .await?;
+                }
+                Ok(orphans) => {
+                    let orphans_list = orphans
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<String>>()
+                        .join("\n");
+
+                    let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                        InlineKeyboardButton::callback("Delete all", "delete_orphans"),
+                        InlineKeyboardButton::callback("Cancel", "cancel"),
+                    ]]);
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+                    send_long_message_with_keyboard(
+                        &bot,
+                        msg.chat.id,
+                        &format!("Orphaned home directories:\n{orphans_list}"),
+                        keyboard,
+                    )
+                    .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
-        Command::UserAdd {
-            username,
-            group,
-            exp_date,
-            password,
-        } => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Config => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            let config_file: config::ConfigFile = CONFIG.clone();
-            match lib::newuser(&username, &group, &password, &exp_date) {
-                Ok(sshuser) => {
-                    bot.send_message(
+            match CONFIG.redacted_json() {
+                Ok(json) => {
+                    bot.send_document(
                         msg.chat.id,
-                        format!("**user info:**\n{sshuser}\n\n**server info:**\n{config_file}"),
+                        InputFile::memory(json.into_bytes()).file_name("userbot.json"),
                     )
-                    .parse_mode(ParseMode::Markdown)
                     .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, format!("Couldn't serialize config: {err}"))
+                        .await?;
+                }
+            }
+        }
+        Command::Export => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
 
-                    let sagernet_link = lib::sagernet_link_generator(
-                        &config_file.server_address,
-                        config_file.ports[0],
-                        &sshuser.username,
-                        &sshuser.password,
-                        &config_file.location,
-                        &sshuser.expiry_date,
-                    );
+            let file_name = format!("users-{}.jsonl", lib::today().format("%Y-%m-%d"));
+            let path = std::env::temp_dir().join(format!("userbot-export-{}.jsonl", msg.id.0));
+
+            let result = std::fs::File::create(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|file| {
+                    lib::export_users_jsonl_to_writer(
+                        &CONFIG.admin_prefix(msg.chat.id.0),
+                        std::io::BufWriter::new(file),
+                    )
+                });
+
+            // Remove the temporary file before propagating a failed upload.
+            let sent = match result {
+                Ok(_) => bot
+                    .send_document(msg.chat.id, InputFile::file(&path).file_name(file_name))
+                    .await
+                    .map(|_| ()),
+                Err(err) => bot
+                    .send_message(msg.chat.id, format!("Couldn't export users: {err}"))
+                    .await
+                    .map(|_| ()),
+            };
 
-                    let qr_bytes = lib::encode_qr_code_to_image_bytes(&sagernet_link);
-                    let input_file = InputFile::memory(qr_bytes);
+            if let Err(err) = std::fs::remove_file(&path) {
+                log::warn!("Couldn't remove {}: {err}", path.display());
+            }
+
+            sent?;
+        }
+        Command::ApiStatus => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            bot.send_message(msg.chat.id, api_status())
+                .parse_mode(ParseMode::Markdown)
+                .await?;
+        }
+        Command::Load => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
 
-                    bot.send_photo(msg.chat.id, input_file)
-                        .caption(format!(
-                            "**{}** {}\n`{sagernet_link}`",
-                            &sshuser.username, &sshuser.expiry_date
-                        ))
+            match lib::server_resources() {
+                Ok(server_resources) => {
+                    bot.send_message(msg.chat.id, format!("{server_resources}"))
                         .parse_mode(ParseMode::Markdown)
                         .await?;
-
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
             }
         }
-        Command::AutoAdd { group, days } => {
-            if !CONFIG.admin_list.contains(&msg.chat.id.0) {
+        Command::Whois(chat_id) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
                 return Ok(());
             }
 
-            let config_file: config::ConfigFile = CONFIG.clone();
-            match lib::auto_newuser(&config_file.prefix, &group, days) {
-                Ok(sshuser) => {
+            let reply = match chat_id.trim().parse::<i64>() {
+                Ok(chat_id) => match resolve_chat_name(&bot, chat_id).await {
+                    Ok(name) => format!("{chat_id}: {name}"),
+                    Err(err) => format!(
+                        "Couldn't resolve {chat_id}, the bot only knows chats that talked to it: {err}"
+                    ),
+                },
+                Err(_) => "Invalid chat id".to_string(),
+            };
+
+            bot.send_message(msg.chat.id, reply).await?;
+        }
+        Command::Recent(minutes) => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let mut args = minutes.split_whitespace();
+            let minutes = args.next().map_or(Ok(60), |minutes| {
+                minutes
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|minutes| *minutes > 0)
+                    .ok_or("Invalid minutes".to_string())
+            });
+            let page = args.next().map_or(Ok(1), |page| {
+                page.parse::<usize>()
+                    .ok()
+                    .filter(|page| *page > 0)
+                    .ok_or("Invalid page".to_string())
+            });
+
+            match minutes.and_then(|minutes| Ok((minutes, page?, lib::audit::recent(minutes)))) {
+                Ok((minutes, _, entries)) if entries.is_empty() => {
                     bot.send_message(
                         msg.chat.id,
-                        format!("**user info:**\n{sshuser}\n\n**server info:**\n{config_file}"),
+                        format!("No commands in the last {minutes} minutes"),
                     )
-                    .parse_mode(ParseMode::Markdown)
                     .await?;
+                }
+                Ok((minutes, page, entries)) => {
+                    let pages = entries.len().div_ceil(RECENT_PAGE_SIZE);
+                    let page = page.min(pages);
 
-                    let sagernet_link = lib::sagernet_link_generator(
-                        &config_file.server_address,
-                        config_file.ports[0],
-                        &sshuser.username,
-                        &sshuser.password,
-                        &config_file.location,
-                        &sshuser.expiry_date,
-                    );
+                    let mut reply = entries
+                        .iter()
+                        .skip((page - 1) * RECENT_PAGE_SIZE)
+                        .take(RECENT_PAGE_SIZE)
+                        .map(|entry| entry.to_string())
+                        .collect::<Vec<String>>()
+                        .join("\n");
 
-                    let qr_bytes = lib::encode_qr_code_to_image_bytes(&sagernet_link);
-                    let input_file = InputFile::memory(qr_bytes);
+                    reply.push_str(&format!("\n\nPage {page} of {pages}"));
+                    if page < pages {
+                        reply.push_str(&format!(", next: /recent {minutes} {}", page + 1));
+                    }
 
-                    bot.send_photo(msg.chat.id, input_file)
-                        .caption(format!(
-                            "**{}** {}\n`{sagernet_link}`",
-                            &sshuser.username, &sshuser.expiry_date
-                        ))
+                    bot.send_message(msg.chat.id, reply)
                         .parse_mode(ParseMode::Markdown)
                         .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
+                }
+            }
+        }
+        Command::SelfTest => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
+
+            let me = bot.get_me().await?;
+
+            let log_chat_status = match bot
+                .send_message(ChatId(CONFIG.log_chat), "Self-test message")
+                .await
+            {
+                Ok(_) => "OK".to_string(),
+                Err(err) => format!("failed ({err})"),
+            };
+
+            let admins = CONFIG
+                .admins()
+                .iter()
+                .map(|admin| admin.to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+
+            bot.send_message(
+                msg.chat.id,
+                format!(
+                    "bot: @{}\nlog chat {}: {log_chat_status}\nadmins: {admins}",
+                    me.username(),
+                    CONFIG.log_chat
+                ),
+            )
+            .await?;
+        }
+        Command::ReloadAdmins => {
+            if !CONFIG.is_admin(msg.chat.id.0) {
+                return Ok(());
+            }
 
-                    bot.forward_message(ChatId(CONFIG.log_chat), msg.chat.id, msg.id)
-                        .await?
+            match CONFIG.reload_admin_list_file() {
+                Ok(count) => {
+                    bot.send_message(msg.chat.id, format!("Loaded {count} admins from file"))
+                        .await?;
+                }
+                Err(err) => {
+                    bot.send_message(msg.chat.id, err).await?;
                 }
-                Err(err) => bot.send_message(msg.chat.id, err).await?,
             }
         }
     };
@@ -287,3 +2835,278 @@ async fn answer(bot: Bot, msg: Message, cmd: Command) -> ResponseResult<()> {
     Ok(())
 }
 
+/// Creates a user on behalf of an admin, enforcing their quota and recording them as the owner.
+fn create_owned_user(
+    owner: i64,
+    create: impl FnOnce() -> Result<lib::SSHUser, String>,
+) -> Result<lib::SSHUser, String> {
+    lib::check_quota(owner)?;
+
+    let sshuser = create()?;
+
+    if let Err(err) = lib::set_user_owner(&sshuser.username, owner) {
+        log::warn!("Couldn't record owner of {}: {err}", sshuser.username);
+    }
+
+    Ok(sshuser)
+}
+
+/// Replies with the fields parsed from a user creation command when `echo_parsed` is set, so a
+/// mis-ordered argument is noticed, e.g. `Creating user=alice group=max2 expiry=2024-01-31`.
+async fn echo_parsed(bot: &Bot, msg: &Message, fields: &[(&str, &str)]) -> ResponseResult<()> {
+    if !CONFIG.echo_parsed {
+        return Ok(());
+    }
+
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect();
+
+    bot.send_message(msg.chat.id, format!("Creating {}", fields.join(" ")))
+        .await?;
+
+    Ok(())
+}
+
+/// Bounds the images rendered at once to `max_image_jobs`.
+static IMAGE_JOBS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Runs CPU-bound image rendering on the blocking thread pool, at most `max_image_jobs` at once,
+/// so a burst of new users doesn't stall the async workers that answer other commands.
+async fn render_image<T: Send + 'static>(
+    render: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    let _permit = IMAGE_JOBS
+        .get_or_init(|| Semaphore::new(CONFIG.max_image_jobs.max(1)))
+        .acquire()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    tokio::task::spawn_blocking(render)
+        .await
+        .map_err(|err| format!("Rendering failed: {err}"))?
+}
+
+/// Renders the PNG QR code of a link with `render_image`.
+async fn render_qr_code(link: &str) -> Result<Vec<u8>, String> {
+    let link = link.to_string();
+
+    render_image(move || Ok(lib::encode_qr_code_to_image_bytes(&link))).await
+}
+
+/// Tells the admin when a user was created under a variant of the username they asked for,
+/// because it was taken.
+async fn report_username_variant(
+    bot: &Bot,
+    msg: &Message,
+    username: &str,
+    sshuser: &lib::SSHUser,
+) -> ResponseResult<()> {
+    if sshuser.username != username {
+        bot.send_message(
+            msg.chat.id,
+            format!("{username} is taken, created {} instead", sshuser.username),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Sends the info and QR code of a newly created user, then forwards the command to the log chat.
+async fn send_new_user(bot: &Bot, msg: &Message, sshuser: &lib::SSHUser) -> ResponseResult<()> {
+    send_user_details(bot, msg.chat.id, sshuser).await?;
+
+    log_command(bot, msg);
+
+    Ok(())
+}
+
+/// Sends the info and QR code of a newly created user to a chat.
+///
+/// If the QR code can't be sent, the link is sent as text instead so the admin still gets it.
+async fn send_user_details(
+    bot: &Bot,
+    chat_id: ChatId,
+    sshuser: &lib::SSHUser,
+) -> ResponseResult<()> {
+    let config_file: config::ConfigFile = CONFIG.clone();
+
+    if let Some(home_template) = &config_file.home_template {
+        if let Err(err) = lib::install_home_template(
+            &sshuser.username,
+            &config_file.server_address,
+            home_template,
+        ) {
+            bot.send_message(chat_id, format!("Couldn't install home template: {err}"))
+                .await?;
+        }
+    }
+
+    if config_file.force_password_change {
+        if let Err(err) = lib::require_password_change(&sshuser.username) {
+            bot.send_message(chat_id, format!("Couldn't force a password change: {err}"))
+                .await?;
+        }
+    }
+
+    let user_info = format!("**user info:**\n{sshuser}\n\n**server info:**\n{config_file}");
+
+    if let Some(welcome_image) = &config_file.welcome_image_bytes {
+        if let Err(err) = bot
+            .send_photo(chat_id, InputFile::memory(welcome_image.to_vec()))
+            .await
+        {
+            record_rate_limit(&err);
+            log::warn!("Couldn't send welcome image: {err}");
+        }
+    }
+
+    // The user already exists at this point, so fall back to plain text rather than leaving the
+    // admin without the credentials.
+    if let Err(err) = bot
+        .send_message(chat_id, &user_info)
+        .parse_mode(ParseMode::Markdown)
+        .await
+    {
+        record_rate_limit(&err);
+        log::warn!("Couldn't send user info as Markdown: {err}");
+        bot.send_message(chat_id, user_info).await?;
+    }
+
+    match lib::sagernet_link_generator(
+        &config_file.server_address,
+        config_file.ports[0],
+        &sshuser.username,
+        &sshuser.password,
+        &config_file.location,
+        &sshuser.expiry_date,
+    ) {
+        Ok(sagernet_link) => {
+            let caption = if config_file.show_raw_link {
+                format!(
+                    "**{}** {}\n`{sagernet_link}`",
+                    &sshuser.username,
+                    lib::display_date(&sshuser.expiry_date)
+                )
+            } else {
+                format!(
+                    "**{}** {}",
+                    &sshuser.username,
+                    lib::display_date(&sshuser.expiry_date)
+                )
+            };
+
+            let sent = match render_qr_code(&sagernet_link).await {
+                Ok(qr_bytes) => bot
+                    .send_photo(chat_id, InputFile::memory(qr_bytes))
+                    .caption(caption)
+                    .parse_mode(ParseMode::Markdown)
+                    .await
+                    .map(|_| ())
+                    .map_err(|err| {
+                        record_rate_limit(&err);
+                        err.to_string()
+                    }),
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = sent {
+                log::error!("Couldn't send QR code: {err}");
+                bot.send_message(
+                    chat_id,
+                    format!("Couldn't send the QR code ({err}), here is the link instead:\n{sagernet_link}"),
+                )
+                .await?;
+            }
+
+            if config_file.qr_svg {
+                match lib::encode_qr_code_to_svg(&sagernet_link) {
+                    Ok(qr_svg) => {
+                        bot.send_document(
+                            chat_id,
+                            InputFile::memory(qr_svg.into_bytes())
+                                .file_name(format!("{}.svg", sshuser.username)),
+                        )
+                        .await?;
+                    }
+                    Err(err) => {
+                        bot.send_message(chat_id, format!("Couldn't generate SVG QR code: {err}"))
+                            .await?;
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            bot.send_message(chat_id, format!("Couldn't generate Sagernet link: {err}"))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_args_skips_repeated_whitespace() {
+        assert_eq!(
+            split_args::<3>("  alice   max2\t\t2024-01-31 ").unwrap(),
+            ["alice", "max2", "2024-01-31"]
+        );
+    }
+
+    #[test]
+    fn split_args_keeps_spaces_in_last_argument() {
+        assert_eq!(
+            split_args::<2>("alice  correct horse  battery").unwrap(),
+            ["alice", "correct horse  battery"]
+        );
+    }
+
+    #[test]
+    fn split_args_reports_missing_arguments() {
+        assert!(matches!(
+            split_args::<4>("alice max2"),
+            Err(ParseError::TooFewArguments {
+                expected: 4,
+                found: 2,
+                ..
+            })
+        ));
+        assert!(split_args::<2>("   ").is_err());
+    }
+
+    #[test]
+    fn useradd_parses_password_with_spaces() {
+        let cmd = Command::parse("/useradd  alice\tmax2  2024-01-31  my pass", "bot").unwrap();
+
+        assert!(matches!(
+            cmd,
+            Command::UserAdd { username, group, exp_date, password }
+                if username == "alice"
+                    && group == "max2"
+                    && exp_date == "2024-01-31"
+                    && password == "my pass"
+        ));
+    }
+
+    #[test]
+    fn split_message_keeps_every_line() {
+        let lines: Vec<String> = (0..1000)
+            .map(|i| format!("/home/user{i:03}: orphaned"))
+            .collect();
+        let text = lines.join("\n");
+
+        let chunks = split_message(&text);
+
+        assert!(chunks.len() > 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.chars().count() <= MAX_MESSAGE_CHARS));
+        assert_eq!(chunks.join("\n"), text);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::config;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+/// Held for every read-modify-write of `schedule_file`, so concurrent commands and the schedule
+/// task can't drop each other's changes.
+static SCHEDULE_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_schedules() -> MutexGuard<'static, ()> {
+    SCHEDULE_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Represents a user creation scheduled for a later date.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduledUser {
+    pub id: u64,
+    pub admin: i64,
+    pub activate_date: String,
+    pub group: String,
+    pub days: i64,
+    /// Number of failed attempts to create the user, it's retried on every check until it works.
+    #[serde(default)]
+    pub failures: u32,
+}
+
+impl fmt::Display for ScheduledUser {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            formatter,
+            "id: `{}`\nactivate date: `{}`\ngroup: `{}`\ndays: `{}`\nadmin: `{}`",
+            self.id,
+            crate::display_date(&self.activate_date),
+            self.group,
+            self.days,
+            self.admin
+        )?;
+
+        if self.failures > 0 {
+            write!(formatter, "\nfailed attempts: `{}`", self.failures)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lists the pending creations recorded in `schedule_file`, oldest first.
+///
+/// # Returns
+///
+/// A `Result` containing the pending creations, or an error message if the file can't be read or
+/// is corrupt. A missing file means there are none.
+pub fn scheduled_users() -> Result<Vec<ScheduledUser>, String> {
+    let _lock = lock_schedules();
+    load_scheduled_users()
+}
+
+fn load_scheduled_users() -> Result<Vec<ScheduledUser>, String> {
+    let path = &config::CONFIG.schedule_file;
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Couldn't read {}: {}", path, e)),
+    };
+
+    parse_scheduled_users(&content).map_err(|e| format!("Couldn't parse {}: {}", path, e))
+}
+
+fn parse_scheduled_users(content: &str) -> Result<Vec<ScheduledUser>, String> {
+    if content.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    serde_json::from_str(content).map_err(|e| e.to_string())
+}
+
+fn save_scheduled_users(scheduled_users: &[ScheduledUser]) -> Result<(), String> {
+    let path = &config::CONFIG.schedule_file;
+    let content = serde_json::to_string_pretty(scheduled_users).map_err(|e| e.to_string())?;
+
+    // Write a temporary file and rename it, so a failed write can't lose the other schedules.
+    let temp_path = format!("{}.tmp", path);
+    std::fs::write(&temp_path, content + "\n")
+        .and_then(|_| std::fs::rename(&temp_path, path))
+        .map_err(|e| format!("Couldn't write {}: {}", path, e))
+}
+
+/// Schedules the creation of a user with `auto_newuser` on a later date.
+///
+/// # Arguments
+///
+/// * `admin` - The chat ID of the admin that owns the user and is notified once it's created.
+/// * `activate_date` - The date to create the user on, in `YYYY-MM-DD` format.
+/// * `group` - The user group for the new SSH user.
+/// * `days` - The number of days until the account expiry, counted from the activation date.
+///
+/// # Returns
+///
+/// A `Result` containing the `ScheduledUser` if successful, or an error message if the date is
+/// invalid or in the past, the group isn't allowed or the schedule can't be saved.
+pub fn schedule_user(
+    admin: i64,
+    activate_date: &str,
+    group: &str,
+    days: i64,
+) -> Result<ScheduledUser, String> {
+    let date = NaiveDate::parse_from_str(activate_date, "%Y-%m-%d")
+        .map_err(|_| "Invalid activation date, use e.g. 2024-01-31".to_string())?;
+
+    if date < crate::today() {
+        return Err("Activation date is in the past".to_string());
+    }
+
+    crate::check_allowed_group(group)?;
+    crate::check_max_logins(group)?;
+
+    let _lock = lock_schedules();
+    let mut scheduled_users = load_scheduled_users()?;
+    let scheduled_user = ScheduledUser {
+        id: scheduled_users.iter().map(|s| s.id).max().unwrap_or(0) + 1,
+        admin,
+        activate_date: date.format("%Y-%m-%d").to_string(),
+        group: group.to_string(),
+        days,
+        failures: 0,
+    };
+
+    scheduled_users.push(scheduled_user.clone());
+    save_scheduled_users(&scheduled_users)?;
+
+    Ok(scheduled_user)
+}
+
+/// Cancels a pending creation. Admins can only cancel their own schedules, super-admins can
+/// cancel any.
+///
+/// # Returns
+///
+/// A `Result` containing the cancelled `ScheduledUser`, or an error message if there is no such
+/// schedule or the schedule can't be saved.
+pub fn cancel_schedule(id: u64, admin: i64) -> Result<ScheduledUser, String> {
+    let _lock = lock_schedules();
+    let mut scheduled_users = load_scheduled_users()?;
+    let index = scheduled_users
+        .iter()
+        .position(|s| s.id == id && (s.admin == admin || crate::is_super_admin(admin)))
+        .ok_or(format!("Schedule {} not found", id))?;
+
+    let scheduled_user = scheduled_users.remove(index);
+    save_scheduled_users(&scheduled_users)?;
+
+    Ok(scheduled_user)
+}
+
+/// Returns the pending creations whose activation date has come. They stay in `schedule_file`
+/// until `complete` is called, so a failed creation is retried on the next check.
+pub fn due() -> Result<Vec<ScheduledUser>, String> {
+    let today = crate::today().format("%Y-%m-%d").to_string();
+
+    Ok(scheduled_users()?
+        .into_iter()
+        .filter(|s| s.activate_date <= today)
+        .collect())
+}
+
+/// Removes a pending creation once its user was created. Does nothing if it was cancelled in the
+/// meantime.
+pub fn complete(id: u64) -> Result<(), String> {
+    let _lock = lock_schedules();
+    let mut scheduled_users = load_scheduled_users()?;
+    let count = scheduled_users.len();
+    scheduled_users.retain(|s| s.id != id);
+
+    if scheduled_users.len() != count {
+        save_scheduled_users(&scheduled_users)?;
+    }
+
+    Ok(())
+}
+
+/// Records a failed attempt to create a pending creation.
+///
+/// # Returns
+///
+/// A `Result` containing the number of failed attempts so far, or an error message if the
+/// schedule can't be saved.
+pub fn record_failure(id: u64) -> Result<u32, String> {
+    let _lock = lock_schedules();
+    let mut scheduled_users = load_scheduled_users()?;
+    let scheduled_user = scheduled_users
+        .iter_mut()
+        .find(|s| s.id == id)
+        .ok_or(format!("Schedule {} not found", id))?;
+
+    scheduled_user.failures += 1;
+    let failures = scheduled_user.failures;
+    save_scheduled_users(&scheduled_users)?;
+
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_scheduled_users_rejects_corrupt_files() {
+        assert!(parse_scheduled_users("[{\"id\": 1,").is_err());
+        assert!(parse_scheduled_users("{}").is_err());
+    }
+
+    #[test]
+    fn parse_scheduled_users_reads_empty_and_old_files() {
+        assert!(parse_scheduled_users("").unwrap().is_empty());
+        assert!(parse_scheduled_users("[]").unwrap().is_empty());
+
+        let scheduled_users = parse_scheduled_users(
+            r#"[{"id": 3, "admin": 42, "activate_date": "2024-01-31", "group": "max2", "days": 30}]"#,
+        )
+        .unwrap();
+        assert_eq!(scheduled_users.len(), 1);
+        assert_eq!(scheduled_users[0].id, 3);
+        assert_eq!(scheduled_users[0].failures, 0);
+    }
+}